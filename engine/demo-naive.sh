@@ -1 +1 @@
-cargo run -- --verbose repl index.fst postings.lmdb tdf.lmdb norm.lmdb docs.lmdb --method=naive --lower
+cargo run -- --verbose repl index.fst --postings postings.lmdb levenshtein-1 --lower
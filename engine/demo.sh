@@ -1 +1,5 @@
-cargo run -- --verbose repl index.fst postings.lmdb tdf.lmdb norm.lmdb docs.lmdb --method=ascending --lower
+cargo run -- preindex ../OpenSubtitles2016/xml/fi/ preindex.dat stats.lmdb --lower --force
+cargo run -- fstindex preindex.dat index.fst postings.lmdb --force
+cargo run -- pack index.fst postings.lmdb index.pack --force
+cargo run -- stats index.pack --termstats stats.lmdb
+cargo run -- --verbose repl index.pack ../fst/fixer.fst --lower
@@ -0,0 +1,285 @@
+//! OPUS sentence alignments.
+//!
+//! OPUS links the sentences of subtitles in different languages with XCES alignment files like
+//!
+//! ```text
+//! <cesAlign version="1.0">
+//! <linkGrp targType="s" fromDoc="en/2002/311519/3528476.xml.gz"
+//!          toDoc="fi/2002/311519/3213.xml.gz">
+//! <link id="SL1" xtargets="1;1" overlap="0.980" />
+//! <link id="SL2" xtargets="2 3;2" overlap="0.911" />
+//! <link id="SL3" xtargets=";4" />
+//! </linkGrp>
+//! </cesAlign>
+//! ```
+//!
+//! Each link gives the ids of sentences in the from document and then the ids of the sentences
+//! in the to document they translate. Either side can be empty.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::mem;
+use std::path::Path;
+use flate2::read::GzDecoder;
+use xml::reader::{EventReader, XmlEvent};
+use xml::common::Position;
+use {Result, ErrorKind, Malformed, Recovery, get_value, req_value, parse_id};
+
+#[derive(Clone, Debug)]
+pub struct Link {
+    pub from: Vec<u64>,
+    pub to: Vec<u64>,
+    /// How well the times of the sentences overlap, from 0 to 1, where given
+    pub overlap: Option<f64>,
+}
+
+/// The links between the sentences of two subtitle files.
+#[derive(Clone, Debug)]
+pub struct LinkGroup {
+    /// The path of the from file, relative to the root of the OPUS release
+    pub from_doc: String,
+    pub to_doc: String,
+    pub links: Vec<Link>,
+}
+
+pub struct AlignmentStream<T> where T: Read {
+    er: EventReader<T>,
+    /// The link group being read
+    group: Option<LinkGroup>,
+    /// Set while skipping the links of a malformed link group
+    skipping_group: bool,
+    finished: bool,
+    pub recovery: Recovery,
+    pub warnings: Vec<Malformed>,
+}
+
+fn parse_ids(ids: &str) -> ::std::result::Result<Vec<u64>, String> {
+    ids.split_whitespace().map(parse_id).collect()
+}
+
+/// Reads xtargets like `2 3;2`.
+fn parse_xtargets(xtargets: &str) -> ::std::result::Result<(Vec<u64>, Vec<u64>), String> {
+    let sides: Vec<&str> = xtargets.split(';').collect();
+    if sides.len() != 2 {
+        return Err(format!("bad xtargets '{}': expected one ';'", xtargets));
+    }
+    Ok((parse_ids(sides[0])?, parse_ids(sides[1])?))
+}
+
+impl AlignmentStream<Box<Read>> {
+    /// Opens an alignment file, which is decompressed if its name ends with `.gz`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ::std::io::Result<AlignmentStream<Box<Read>>> {
+        let input = BufReader::new(File::open(&path)?);
+        let input: Box<Read> = if path.as_ref().to_string_lossy().ends_with(".gz") {
+            Box::new(GzDecoder::new(input)?)
+        } else {
+            Box::new(input)
+        };
+        Ok(AlignmentStream::new(input))
+    }
+}
+
+impl<T: Read> AlignmentStream<T> {
+    pub fn new(alignment_stream: T) -> AlignmentStream<T> {
+        AlignmentStream {
+            er: EventReader::new(alignment_stream),
+            group: None,
+            skipping_group: false,
+            finished: false,
+            recovery: Recovery::Abort,
+            warnings: vec![],
+        }
+    }
+
+    pub fn with_recovery(mut self, recovery: Recovery) -> AlignmentStream<T> {
+        self.recovery = recovery;
+        self
+    }
+
+    fn malformed(&mut self, element: &str, message: String) -> Result<()> {
+        let malformed = Malformed {
+            element: element.to_owned(),
+            position: self.er.position(),
+            message: message,
+        };
+        if self.recovery == Recovery::Skip {
+            self.warnings.push(malformed);
+            Ok(())
+        } else {
+            Err(ErrorKind::MalformedElement(malformed).into())
+        }
+    }
+
+    fn next_group(&mut self) -> Result<Option<LinkGroup>> {
+        loop {
+            match self.er.next()? {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    match name.local_name.as_str() {
+                        "linkGrp" => {
+                            let docs = req_value(&attributes, "fromDoc").and_then(|from_doc| {
+                                req_value(&attributes, "toDoc").map(|to_doc| (from_doc, to_doc))
+                            });
+                            match docs {
+                                Ok((from_doc, to_doc)) => {
+                                    self.group = Some(LinkGroup {
+                                        from_doc: from_doc.to_owned(),
+                                        to_doc: to_doc.to_owned(),
+                                        links: vec![],
+                                    });
+                                    self.skipping_group = false;
+                                }
+                                Err(msg) => {
+                                    self.malformed("linkGrp", msg)?;
+                                    self.group = None;
+                                    self.skipping_group = true;
+                                }
+                            }
+                        }
+                        "link" => {
+                            if self.skipping_group {
+                                continue;
+                            }
+                            let link = req_value(&attributes, "xtargets")
+                                .and_then(|xtargets| parse_xtargets(xtargets));
+                            let (from, to) = match link {
+                                Ok(link) => link,
+                                Err(msg) => {
+                                    self.malformed("link", msg)?;
+                                    continue;
+                                }
+                            };
+                            if self.group.is_none() {
+                                self.malformed("link", "outside any linkGrp".to_owned())?;
+                                continue;
+                            }
+                            let overlap = get_value(&attributes, "overlap")
+                                .and_then(|overlap| overlap.parse::<f64>().ok());
+                            if let Some(ref mut group) = self.group {
+                                group.links.push(Link {
+                                    from: from,
+                                    to: to,
+                                    overlap: overlap,
+                                });
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                XmlEvent::EndElement { name } => {
+                    if name.local_name == "linkGrp" {
+                        self.skipping_group = false;
+                        if let Some(group) = mem::replace(&mut self.group, None) {
+                            return Ok(Some(group));
+                        }
+                    }
+                }
+                XmlEvent::EndDocument => {
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Iterates over the link groups. Stops for good after the end of the document or the first
+/// error.
+impl<T: Read> Iterator for AlignmentStream<T> {
+    type Item = Result<LinkGroup>;
+
+    fn next(&mut self) -> Option<Result<LinkGroup>> {
+        if self.finished {
+            return None;
+        }
+        match self.next_group() {
+            Ok(Some(group)) => Some(Ok(group)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Recovery;
+    use super::{AlignmentStream, LinkGroup, parse_xtargets};
+
+    const ALIGNMENT: &'static str = r#"<?xml version="1.0" encoding="utf-8"?>
+<cesAlign version="1.0">
+<link id="SL0" xtargets="1;1" />
+<linkGrp targType="s" fromDoc="en/2002/311519/3528476.xml.gz">
+<link id="SL1" xtargets="1;1" />
+</linkGrp>
+<linkGrp targType="s" fromDoc="en/1.xml.gz" toDoc="fi/2.xml.gz">
+<link id="SL1" xtargets="1;1" overlap="0.980" />
+<link id="SL2" xtargets="2 x;2" />
+<link id="SL3" xtargets=";4" />
+</linkGrp>
+</cesAlign>
+"#;
+
+    fn read(recovery: Recovery) -> (Vec<LinkGroup>, Option<String>, usize) {
+        let mut alignment = AlignmentStream::new(ALIGNMENT.as_bytes()).with_recovery(recovery);
+        let mut groups = vec![];
+        let mut error = None;
+        for group in &mut alignment {
+            match group {
+                Ok(group) => groups.push(group),
+                Err(err) => error = Some(err.to_string()),
+            }
+        }
+        (groups, error, alignment.warnings.len())
+    }
+
+    #[test]
+    fn xtargets() {
+        assert_eq!(parse_xtargets("2 3;2"), Ok((vec![2, 3], vec![2])));
+        assert_eq!(parse_xtargets(";4"), Ok((vec![], vec![4])));
+        assert_eq!(parse_xtargets(" ; "), Ok((vec![], vec![])));
+        assert!(parse_xtargets("1 2").is_err());
+        assert!(parse_xtargets("1;2;3").is_err());
+        assert!(parse_xtargets("1;b").is_err());
+    }
+
+    #[test]
+    fn skip() {
+        let (groups, error, warnings) = read(Recovery::Skip);
+        assert_eq!(error, None);
+        // The link outside a group, the group without toDoc and the link with a bad id
+        assert_eq!(warnings, 3);
+        assert_eq!(groups.len(), 1);
+        let group = &groups[0];
+        assert_eq!((group.from_doc.as_str(), group.to_doc.as_str()),
+                   ("en/1.xml.gz", "fi/2.xml.gz"));
+        let links = group.links.iter()
+            .map(|link| (link.from.clone(), link.to.clone(), link.overlap))
+            .collect::<Vec<_>>();
+        assert_eq!(links, vec![(vec![1], vec![1], Some(0.98)), (vec![], vec![4], None)]);
+    }
+
+    #[test]
+    fn abort() {
+        let (groups, error, warnings) = read(Recovery::Abort);
+        assert!(groups.is_empty());
+        assert!(error.unwrap().contains("outside any linkGrp"));
+        assert_eq!(warnings, 0);
+
+        let without_to_doc = ALIGNMENT.replace("<link id=\"SL0\" xtargets=\"1;1\" />\n", "");
+        let groups = AlignmentStream::new(without_to_doc.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].as_ref().unwrap_err().to_string().contains("toDoc"));
+
+        let bad_id = without_to_doc.replace(
+            "<linkGrp targType=\"s\" fromDoc=\"en/2002/311519/3528476.xml.gz\">\n\
+             <link id=\"SL1\" xtargets=\"1;1\" />\n</linkGrp>\n", "");
+        let groups = AlignmentStream::new(bad_id.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].as_ref().unwrap_err().to_string().contains("bad id 'x'"));
+    }
+}
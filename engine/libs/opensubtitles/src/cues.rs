@@ -0,0 +1,351 @@
+//! SRT and WebVTT subtitles.
+//!
+//! Each cue becomes a block. Its text is split into words by `tokenize` and into sentences at
+//! sentence-final punctuation and dialogue dashes, so that sentences can run over several cues as
+//! they do in OpenSubtitles. Sentences are numbered from 1, as are the words of each sentence.
+//! Markup like `<i>`, `<v Speaker>` and `{\an8}` is dropped. Files which aren't UTF-8 are taken to
+//! be Latin-1, which most older Finnish subtitles are near enough.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use xml::common::TextPosition;
+use duration::{self, parse_duration};
+use {Result, ErrorKind, Malformed, Meta, ParseMode, Recovery, SubStreamBit, SentDelim,
+     BlockDelim, DelimType, Word, Sentences, tokenize};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CueFormat {
+    Srt,
+    WebVtt,
+}
+
+/// The bits of an SRT or WebVTT file. The whole file is read up front.
+pub struct CueStream {
+    bits: VecDeque<Result<SubStreamBit>>,
+    /// Always empty, since these formats have no metadata
+    meta: Meta,
+    pub warnings: Vec<Malformed>,
+}
+
+/// Keeps track of sentences while turning cues into bits.
+struct Builder {
+    bits: VecDeque<Result<SubStreamBit>>,
+    sent_id: u64,
+    word_id: u64,
+    in_sent: bool,
+    /// Set after sentence-final punctuation, which closing marks may still follow
+    ending: bool,
+}
+
+impl Builder {
+    fn push(&mut self, bit: SubStreamBit) {
+        self.bits.push_back(Ok(bit));
+    }
+
+    fn word(&mut self, token: String) {
+        if self.ending && !token.chars().all(|c| !c.is_alphanumeric() && c != '-') {
+            self.end_sentence();
+        }
+        if is_sentence_final(&token) {
+            self.ending = true;
+        }
+        if !self.in_sent {
+            self.sent_id += 1;
+            self.word_id = 0;
+            self.in_sent = true;
+            let sent_id = self.sent_id;
+            self.push(SubStreamBit::SentDelim(SentDelim {
+                id: sent_id,
+                delim_type: DelimType::Start,
+            }));
+        }
+        self.word_id += 1;
+        let word_id = self.word_id;
+        self.push(SubStreamBit::Word(Word::new(word_id, token)));
+    }
+
+    fn end_sentence(&mut self) {
+        self.ending = false;
+        if self.in_sent {
+            self.in_sent = false;
+            let sent_id = self.sent_id;
+            self.push(SubStreamBit::SentDelim(SentDelim {
+                id: sent_id,
+                delim_type: DelimType::End,
+            }));
+        }
+    }
+}
+
+fn decode(bytes: Vec<u8>) -> String {
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(err) => err.into_bytes().into_iter().map(|byte| byte as char).collect(),
+    };
+    text.trim_left_matches('\u{feff}').to_owned()
+}
+
+/// Drops markup and decodes the entities WebVTT has.
+fn strip_markup(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut closer = None;
+    for c in line.chars() {
+        match closer {
+            Some(end) => {
+                if c == end {
+                    closer = None;
+                }
+            }
+            None => {
+                match c {
+                    '<' => closer = Some('>'),
+                    '{' => closer = Some('}'),
+                    _ => text.push(c),
+                }
+            }
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&lrm;", "")
+        .replace("&rlm;", "")
+        .replace("&amp;", "&")
+}
+
+fn is_sentence_final(token: &str) -> bool {
+    token.chars().all(|c| c == '.' || c == '!' || c == '?' || c == '…')
+}
+
+fn parse_timestamp(timestamp: &str, format: CueFormat, mode: ParseMode)
+        -> ::std::result::Result<Duration, String> {
+    let offset = match (mode, format) {
+        (ParseMode::Lax, _) => duration::lax::parse_duration(timestamp),
+        (ParseMode::Strict, CueFormat::Srt) => parse_duration(timestamp),
+        (ParseMode::Strict, CueFormat::WebVtt) => {
+            // Like 00:01:31.950, where the hours can be left out
+            let timestamp = timestamp.replace('.', ",");
+            if timestamp.matches(':').count() == 1 {
+                parse_duration(&format!("00:{}", timestamp))
+            } else {
+                parse_duration(&timestamp)
+            }
+        }
+    };
+    offset.map_err(|err| format!("bad timestamp '{}': {}", timestamp, err))
+}
+
+/// Reads the times of a line like `00:01:31,950 --> 00:01:34,100 align:start`.
+fn parse_timing(line: &str, format: CueFormat, mode: ParseMode)
+        -> ::std::result::Result<(Duration, Duration), String> {
+    let mut bits = line.splitn(2, "-->");
+    let start = bits.next().unwrap().trim();
+    let end = bits.next()
+        .and_then(|rest| rest.split_whitespace().next())
+        .ok_or_else(|| format!("no end time in '{}'", line))?;
+    Ok((parse_timestamp(start, format, mode)?, parse_timestamp(end, format, mode)?))
+}
+
+impl CueStream {
+    pub fn from_path<P: AsRef<Path>>(path: P, format: CueFormat, mode: ParseMode,
+                                     recovery: Recovery) -> ::std::io::Result<CueStream> {
+        CueStream::new(File::open(path)?, format, mode, recovery)
+    }
+
+    /// Reads a whole file. Malformed cue timings are skipped with a warning in lax mode or when
+    /// skipping, and otherwise end the stream with an error.
+    pub fn new<R: Read>(mut input: R, format: CueFormat, mode: ParseMode, recovery: Recovery)
+            -> ::std::io::Result<CueStream> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        let text = decode(bytes);
+        let mut builder = Builder {
+            bits: VecDeque::new(),
+            sent_id: 0,
+            word_id: 0,
+            in_sent: false,
+            ending: false,
+        };
+        let mut warnings = vec![];
+        let mut block_id = 0;
+        // Cues are separated by blank lines. Lines before the timing, like SRT numbers and
+        // WebVTT identifiers, are ignored, as are blocks without a timing like WEBVTT and NOTE.
+        let mut lines = text.lines().map(|line| line.trim_right()).enumerate().peekable();
+        while lines.peek().is_some() {
+            let block = lines.by_ref()
+                .skip_while(|&(_, line)| line.is_empty())
+                .take_while(|&(_, line)| !line.is_empty())
+                .collect::<Vec<_>>();
+            let timing_idx = match block.iter().position(|&(_, line)| line.contains("-->")) {
+                Some(timing_idx) => timing_idx,
+                None => continue,
+            };
+            let (row, timing) = block[timing_idx];
+            block_id += 1;
+            let (start, end) = match parse_timing(timing, format, mode) {
+                Ok(times) => times,
+                Err(message) => {
+                    let malformed = Malformed {
+                        element: "cue".to_owned(),
+                        position: TextPosition { row: row as u64, column: 0 },
+                        message: message,
+                    };
+                    if recovery == Recovery::Skip || mode == ParseMode::Lax {
+                        warnings.push(malformed);
+                        continue;
+                    }
+                    builder.end_sentence();
+                    builder.bits.push_back(Err(ErrorKind::MalformedElement(malformed).into()));
+                    break;
+                }
+            };
+            builder.push(SubStreamBit::BlockDelim(BlockDelim {
+                id: block_id,
+                offset: start,
+                delim_type: DelimType::Start,
+            }));
+            for &(_, line) in &block[timing_idx + 1..] {
+                let line = strip_markup(line);
+                if line.trim_left().starts_with('-') {
+                    // A dash starts what the next speaker says
+                    builder.end_sentence();
+                }
+                for token in tokenize(&line) {
+                    builder.word(token);
+                }
+            }
+            builder.push(SubStreamBit::BlockDelim(BlockDelim {
+                id: block_id,
+                offset: end,
+                delim_type: DelimType::End,
+            }));
+            // After the end time, so that a sentence ending with the cue gets it
+            if builder.ending {
+                builder.end_sentence();
+            }
+        }
+        builder.end_sentence();
+        Ok(CueStream {
+            bits: builder.bits,
+            meta: Meta::default(),
+            warnings: warnings,
+        })
+    }
+
+    pub fn sentences(&mut self) -> Sentences<&mut CueStream> {
+        Sentences::new(self)
+    }
+
+    pub fn meta(&self) -> Option<&Meta> {
+        Some(&self.meta)
+    }
+}
+
+impl Iterator for CueStream {
+    type Item = Result<SubStreamBit>;
+
+    fn next(&mut self) -> Option<Result<SubStreamBit>> {
+        self.bits.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use {ParseMode, Recovery};
+    use super::{CueStream, CueFormat};
+
+    fn millis(millis: u64) -> Option<Duration> {
+        Some(Duration::from_millis(millis))
+    }
+
+    fn read(input: &[u8], format: CueFormat, mode: ParseMode, recovery: Recovery)
+            -> (Vec<(String, Option<Duration>, Option<Duration>)>, Option<String>, usize) {
+        let mut cues = CueStream::new(input, format, mode, recovery).unwrap();
+        let mut sentences = vec![];
+        let mut error = None;
+        for sentence in cues.sentences() {
+            match sentence {
+                Ok(sentence) => sentences.push((sentence.text(), sentence.start, sentence.end)),
+                Err(err) => error = Some(err.to_string()),
+            }
+        }
+        (sentences, error, cues.warnings.len())
+    }
+
+    fn texts(input: &[u8], format: CueFormat) -> Vec<String> {
+        read(input, format, ParseMode::Strict, Recovery::Abort).0.into_iter()
+            .map(|(text, _, _)| text)
+            .collect()
+    }
+
+    #[test]
+    fn srt() {
+        let srt = b"1\n00:00:01,000 --> 00:00:03,500\nMina menen\n\n\
+                    2\n00:00:04,000 --> 00:00:06,250\nkotiin. Hei!\n";
+        let (sentences, error, warnings) =
+            read(srt, CueFormat::Srt, ParseMode::Strict, Recovery::Abort);
+        assert_eq!(sentences, vec![
+            ("Mina menen kotiin .".to_owned(), millis(1000), millis(3500)),
+            ("Hei !".to_owned(), millis(4000), millis(6250)),
+        ]);
+        assert_eq!((error, warnings), (None, 0));
+    }
+
+    #[test]
+    fn webvtt() {
+        let vtt = b"WEBVTT\n\nNOTE not a cue\n\n\
+                    intro\n01:31.950 --> 01:34.100 align:start\nNo niin.\n\n\
+                    01:00:00.000 --> 01:00:01.500\nJoo.\n";
+        let (sentences, error, _) =
+            read(vtt, CueFormat::WebVtt, ParseMode::Strict, Recovery::Abort);
+        assert_eq!(sentences, vec![
+            ("No niin .".to_owned(), millis(91950), millis(94100)),
+            ("Joo .".to_owned(), millis(3600000), millis(3601500)),
+        ]);
+        assert_eq!(error, None);
+    }
+
+    #[test]
+    fn latin1() {
+        let srt = b"1\n00:00:01,000 --> 00:00:02,000\nP\xe4iv\xe4\xe4!\n";
+        assert_eq!(texts(srt, CueFormat::Srt), vec!["Päivää !"]);
+    }
+
+    #[test]
+    fn markup() {
+        let vtt = b"WEBVTT\n\n00:01.000 --> 00:02.000\n\
+                    {\\an8}<v Anna><i>Tom &amp; Jerry</i> &lt;3\n";
+        assert_eq!(texts(vtt, CueFormat::WebVtt), vec!["Tom & Jerry < 3"]);
+    }
+
+    #[test]
+    fn sentence_splits() {
+        let srt = b"1\n00:00:01,000 --> 00:00:02,000\nMit\xc3\xa4? Ei mit\xc3\xa4\xc3\xa4n...\n\
+                    - Oletko varma\n- Olen\n";
+        assert_eq!(texts(srt, CueFormat::Srt),
+                   vec!["Mitä ?", "Ei mitään ...", "- Oletko varma", "- Olen"]);
+    }
+
+    #[test]
+    fn malformed_timing() {
+        let srt = b"1\n00:00:01,000 --> 00:00:02,000\nEka.\n\n\
+                    2\n00:00:03 --> nope\nToka.\n\n\
+                    3\n00:00:05,000 --> 00:00:06,000\nKolmas.\n";
+        let (sentences, error, warnings) =
+            read(srt, CueFormat::Srt, ParseMode::Strict, Recovery::Skip);
+        assert_eq!(sentences.into_iter().map(|(text, _, _)| text).collect::<Vec<_>>(),
+                   vec!["Eka .", "Kolmas ."]);
+        assert_eq!((error, warnings), (None, 1));
+
+        let (sentences, error, warnings) =
+            read(srt, CueFormat::Srt, ParseMode::Strict, Recovery::Abort);
+        assert_eq!(sentences.into_iter().map(|(text, _, _)| text).collect::<Vec<_>>(),
+                   vec!["Eka ."]);
+        assert!(error.unwrap().contains("cue"));
+        assert_eq!(warnings, 0);
+    }
+}
@@ -15,6 +15,10 @@ use itertools::Itertools;
         #[error_chain(description = r#"|_| "Can't parse duration, wrong number of commas (expected 1)""#)]
         WrongNumberOfCommas(()),
 
+        #[error_chain(custom)]
+        #[error_chain(description = r#"|_| "Can't parse duration, wrong number of fields (expected 2 or 3)""#)]
+        WrongNumberOfFields(usize),
+
         #[error_chain(foreign)]
         ParseIntError(std::num::ParseIntError),
     }
@@ -37,40 +41,59 @@ use itertools::Itertools;
     }
 //}
 
-/*
-
-mod lax {
-    #[derive(Debug, error_chain)]
-    pub enum ErrorKind {
-        Msg(String),
-
-        #[error_chain(custom)]
-        #[error_chain(description = r#"|_| "Can't parse duration, wrong number of fields (expected 3 or 4)""#)]
-        WrongNumberOfFields(u64),
+pub mod lax {
+    use std::time::Duration;
+    use itertools::Itertools;
+    use super::{ErrorKind, Result};
 
-        #[error_chain(custom)]
-        #[error_chain(description = r#"|_| "Can't parse duration, wrong number of commas (expected 1)""#)]
-        WrongNumberOfCommas(()),
-
-        #[error_chain(foreign)]
-        ParseIntError(std::num::ParseIntError),
+    pub fn parse_duration(in_dur: &str) -> Result<Duration> {
+        // Parse broken durations like 0 0:06:29, 01:38:07.100 and 06:29 as well as the usual
+        // 00:01:31,950
+        let in_dur = in_dur.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        let bits = in_dur.split(':').collect_vec();
+        if bits.len() != 2 && bits.len() != 3 {
+            bail!(ErrorKind::WrongNumberOfFields(bits.len()));
+        }
+        let (hours, mins) = if bits.len() == 3 {
+            (bits[0].parse::<u64>()?, bits[1].parse::<u64>()?)
+        } else {
+            (0, bits[0].parse::<u64>()?)
+        };
+        let sec_bits = bits[bits.len() - 1].split(|c: char| c == ',' || c == '.').collect_vec();
+        if sec_bits.len() > 2 {
+            bail!(ErrorKind::WrongNumberOfCommas(()));
+        }
+        let secs = hours * 60 * 60 + mins * 60 + sec_bits[0].parse::<u64>()?;
+        // The fraction is decimal, so 07.1 is 100 milliseconds
+        let millis = match sec_bits.get(1) {
+            Some(frac) if !frac.is_empty() => {
+                let frac = frac.chars().chain("00".chars()).take(3).collect::<String>();
+                frac.parse::<u64>()?
+            }
+            _ => 0,
+        };
+        Ok(Duration::new(secs, (millis * 1_000_000) as u32))
     }
+}
 
-    pub fn parse_int(num: &str) -> IntParseResult {
-        num.parse::<u64>()?
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::lax;
+
+    #[test]
+    fn lax_durations() {
+        assert_eq!(lax::parse_duration("00:01:31,950").ok(), Some(Duration::new(91, 950_000_000)));
+        assert_eq!(lax::parse_duration("0 0:06:29").ok(), Some(Duration::new(389, 0)));
+        assert_eq!(lax::parse_duration("01:38:07.1").ok(), Some(Duration::new(5887, 100_000_000)));
+        assert_eq!(lax::parse_duration("06:29").ok(), Some(Duration::new(389, 0)));
+        assert_eq!(lax::parse_duration("06:29,").ok(), Some(Duration::new(389, 0)));
     }
 
-    pub fn parse_duration(in_dur: &str) -> Result<Duration> {
-        // Parse broken durations like 0 0:06:29 and 01:38:07.100
-        let bits = in_dur.split(&[':', ',', '.']).collect_vec();
-        if bits.len() != 3 && bits.len() != 4 {
-            bail!(ErrorKind::WrongNumberOfFields(bits.len()));
+    #[test]
+    fn lax_broken_durations() {
+        for duration in &["", "12", "1:2:3:4", "00:01:31,9,5", "00:ä:31", "00:01:3ä", "Ä"] {
+            assert!(lax::parse_duration(duration).is_err(), "{} parsed", duration);
         }
-        let secs = parse_int(bits[0])? * 60 * 60 +
-                   bits[1].parse::<u64>()? * 60 + 
-                   sec_bits[0].parse::<u64>()?;
-        let millis = sec_bits[1].parse::<u64>()?;
-        Ok(Duration::new(secs, (millis * 1_000_000) as u32))
     }
 }
-*/
@@ -4,20 +4,60 @@ extern crate flate2;
 #[macro_use] extern crate derive_error_chain;
 extern crate itertools;
 
+use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::time::Duration;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::mem;
 use xml::reader::{EventReader, XmlEvent};
 use xml::attribute::OwnedAttribute;
+use xml::common::{Position, TextPosition};
 use flate2::read::GzDecoder;
 use std::path::Path;
 mod duration;
 mod time_id;
+mod sentence;
+mod meta;
+mod tokenize;
+mod cues;
+mod align;
 use duration::parse_duration;
 use time_id::parse_time_id;
+pub use sentence::{Sentence, Sentences};
+pub use meta::{Meta, SourceMeta, SubtitleMeta, ConversionMeta};
+pub use tokenize::tokenize;
+pub use cues::{CueStream, CueFormat};
+pub use align::{AlignmentStream, LinkGroup, Link};
+
+/// What to do about malformed `<s>`, `<w>` and `<time>` elements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recovery {
+    /// Return an error, ending the stream
+    Abort,
+    /// Skip the element, along with the words of a skipped sentence, and keep a warning
+    Skip,
+}
+
+/// How forgiving to be of malformed `<time>` elements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseMode {
+    /// Any malformed value is an error
+    Strict,
+    /// Recover broken values like `0 0:06:29` and `01:38:07.100` where possible, and otherwise
+    /// skip the element with a warning
+    Lax,
+}
 
+/// Where the words of a sentence come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tokenization {
+    /// `<w>` elements, as in the tokenized releases
+    Words,
+    /// The text directly inside `<s>`, as in the raw releases, split up by `tokenize`. Words get
+    /// ids counting from 1 in each sentence.
+    Raw,
+}
 
 /*
 enum GroupBetweenError {
@@ -48,16 +88,34 @@ pub trait IterExt : Iterator {
 }
 */
 
-type MetaMap = BTreeMap<(String, String), String>;
 type GzFileRead = GzDecoder<BufReader<File>>;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Word {
     pub id: u64,
     pub word: String,
+    /// The annotation of parsed releases, where there is one
+    pub lemma: Option<String>,
+    pub upos: Option<String>,
+    pub xpos: Option<String>,
+    pub feats: Option<String>,
 }
 
-#[derive(Debug)]
+impl Word {
+    /// A word without annotation.
+    pub fn new(id: u64, word: String) -> Word {
+        Word {
+            id: id,
+            word: word,
+            lemma: None,
+            upos: None,
+            xpos: None,
+            feats: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum DelimType {
     Start,
     End
@@ -85,7 +143,7 @@ pub enum SubStreamBit {
 
 #[derive(Debug)]
 pub enum FlatStreamBit {
-    Meta(MetaMap),
+    Meta(Meta),
     SubStreamBit(SubStreamBit),
     EndStream,
 }
@@ -127,8 +185,21 @@ fn get_value<'a>(attrs: &'a Vec<OwnedAttribute>, name: &str) -> Option<&'a Strin
                 .map(|e| &e.value)
 }
 
-fn req_value<'a>(attrs: &'a Vec<OwnedAttribute>, name: &str) -> Result<&'a String> {
-    get_value(attrs, name).ok_or_else(|| ErrorKind::ExpectedAttribute(name.to_owned()).into())
+fn req_value<'a>(attrs: &'a Vec<OwnedAttribute>, name: &str)
+        -> std::result::Result<&'a String, String> {
+    get_value(attrs, name).ok_or_else(|| format!("expected attribute: '{}'", name))
+}
+
+/// An annotation attribute, taking `_` to mean none like CoNLL-U does.
+fn annotation(attrs: &Vec<OwnedAttribute>, name: &str) -> Option<String> {
+    get_value(attrs, name)
+        .map(|value| value.trim())
+        .and_then(|value| if value.is_empty() || value == "_" { None } else { Some(value) })
+        .map(|value| value.to_owned())
+}
+
+fn parse_id(value: &str) -> std::result::Result<u64, String> {
+    value.parse::<u64>().map_err(|err| format!("bad id '{}': {}", value, err))
 }
 
 // Open subtitles iterator
@@ -148,77 +219,190 @@ struct OpenSubtitleParseError {
 }
 */
 
+/// A malformed element along with where it is in the XML.
+#[derive(Clone, Debug)]
+pub struct Malformed {
+    pub element: String,
+    pub position: TextPosition,
+    pub message: String,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: malformed <{}>: {}", self.position, self.element, self.message)
+    }
+}
+
 #[derive(Debug, error_chain)]
 pub enum ErrorKind {
     Msg(String),
 
-    #[error_chain(link="duration::Error")]
-    DurationParseErr(duration::ErrorKind),
-
-    #[error_chain(link="time_id::Error")]
-    TimeIdParseErr(time_id::ErrorKind),
-
-    #[error_chain(foreign)]
-    ParseIntError(std::num::ParseIntError),
+    // Carries its own position
     #[error_chain(foreign)]
     XmlParseError(xml::reader::Error),
 
     #[error_chain(custom)]
-    #[error_chain(description = r#"|_| "Expected attribute""#)]
-    #[error_chain(display = r#"|t| write!(f, "expected attribute: '{}'", t)"#)]
-    ExpectedAttribute(String),
+    #[error_chain(description = r#"|_| "Malformed element""#)]
+    #[error_chain(display = r#"|m| write!(f, "{}", m)"#)]
+    MalformedElement(Malformed),
 }
 
 pub struct OpenSubtitleStream<T> where T: Read {
     pub er: EventReader<T>,
-    pub word_id: Option<u64>,
+    /// The word being read, with its id and annotation but no text yet
+    pub word: Option<Word>,
+    pub tokenization: Tokenization,
+    /// The id of the last word split out of raw text in the current sentence
+    pub raw_word_id: u64,
+    /// Words split out of raw text which are yet to be returned
+    pub pending_words: VecDeque<Word>,
     pub sent_id: u64,
     pub in_meta: bool,
-    pub meta_cat: Option<String>,
-    pub meta_attr: Option<String>,
-    pub meta: MetaMap,
+    /// The elements enclosing the current one inside `<meta>`
+    pub meta_path: Vec<String>,
+    /// The values read so far, by dotted path
+    pub meta: BTreeMap<String, String>,
+    /// The metadata once the whole `<meta>` block has been read
+    pub read_meta: Option<Meta>,
+    /// Set once iteration has reached the end of the document or an error
+    pub finished: bool,
+    pub mode: ParseMode,
+    pub recovery: Recovery,
+    /// Set while skipping the words of a malformed sentence
+    pub skipping_sent: bool,
+    pub warnings: Vec<Malformed>,
 }
 
 impl OpenSubtitleStream<GzFileRead> {
     pub fn from_path<P: AsRef<Path>>(path: P)
             -> std::io::Result<OpenSubtitleStream<GzFileRead>> {
+        OpenSubtitleStream::from_path_with_mode(path, ParseMode::Strict)
+    }
+
+    pub fn from_path_with_mode<P: AsRef<Path>>(path: P, mode: ParseMode)
+            -> std::io::Result<OpenSubtitleStream<GzFileRead>> {
         let subf = File::open(path)?;
         let subf_buf = BufReader::new(subf);
         let subf_dec = GzDecoder::new(subf_buf)?;
-        Ok(OpenSubtitleStream::new(subf_dec))
+        Ok(OpenSubtitleStream::with_mode(subf_dec, mode))
     }
 }
 
-fn both<A, B>(a: Option<A>, b: Option<B>) -> Option<(A, B)> {
-    a.and_then(|a| b.map(|b| (a, b)))
-}
-
-
 impl<T: Read> OpenSubtitleStream<T> {
     pub fn new(subtitle_stream: T) -> OpenSubtitleStream<T> {
+        OpenSubtitleStream::with_mode(subtitle_stream, ParseMode::Strict)
+    }
+
+    pub fn with_mode(subtitle_stream: T, mode: ParseMode) -> OpenSubtitleStream<T> {
         let parser = EventReader::new(subtitle_stream);
         OpenSubtitleStream {
             er: parser,
             sent_id: 0,
-            word_id: None,
+            word: None,
+            tokenization: Tokenization::Words,
+            raw_word_id: 0,
+            pending_words: VecDeque::new(),
             in_meta: false,
-            meta_cat: None,
-            meta_attr: None,
+            meta_path: vec![],
             meta: BTreeMap::new(),
+            read_meta: None,
+            finished: false,
+            mode: mode,
+            recovery: Recovery::Abort,
+            skipping_sent: false,
+            warnings: vec![],
+        }
+    }
+
+    pub fn with_recovery(mut self, recovery: Recovery) -> OpenSubtitleStream<T> {
+        self.recovery = recovery;
+        self
+    }
+
+    pub fn with_tokenization(mut self, tokenization: Tokenization) -> OpenSubtitleStream<T> {
+        self.tokenization = tokenization;
+        self
+    }
+
+    fn parse_time(&self, attributes: &Vec<OwnedAttribute>)
+            -> std::result::Result<BlockDelim, String> {
+        let full_id = req_value(attributes, "id")?;
+        let value = req_value(attributes, "value")?;
+        let (delim_type, num_id) = match self.mode {
+            ParseMode::Strict => parse_time_id(full_id.as_str()),
+            ParseMode::Lax => time_id::lax::parse_time_id(full_id.as_str()),
+        }.map_err(|err| format!("bad id '{}': {}", full_id, err))?;
+        let offset = match self.mode {
+            ParseMode::Strict => parse_duration(value.as_str()),
+            ParseMode::Lax => duration::lax::parse_duration(value.as_str()),
+        }.map_err(|err| format!("bad value '{}': {}", value, err))?;
+        Ok(BlockDelim {
+            id: num_id,
+            offset: offset,
+            delim_type: delim_type,
+        })
+    }
+
+    /// Groups the rest of the subtitle into sentences. The stream is only borrowed so that the
+    /// metadata and warnings can still be read afterwards.
+    pub fn sentences(&mut self) -> Sentences<&mut OpenSubtitleStream<T>> {
+        Sentences::new(self)
+    }
+
+    /// The metadata of the subtitle. OpenSubtitles puts the `<meta>` block after the last
+    /// sentence, so this is usually only available once iteration has finished.
+    pub fn meta(&self) -> Option<&Meta> {
+        self.read_meta.as_ref()
+    }
+
+    /// Either keeps a warning about a malformed element and carries on, or fails, depending on
+    /// the recovery policy. Malformed `<time>` elements are always skipped in lax mode.
+    fn malformed(&mut self, element: &str, message: String) -> Result<()> {
+        let malformed = Malformed {
+            element: element.to_owned(),
+            position: self.er.position(),
+            message: message,
+        };
+        if self.recovery == Recovery::Skip || (element == "time" && self.mode == ParseMode::Lax) {
+            self.warnings.push(malformed);
+            Ok(())
+        } else {
+            Err(ErrorKind::MalformedElement(malformed).into())
         }
     }
 
-    pub fn next(&mut self) -> Result<FlatStreamBit> {
+    /// Reads the next bit of the document, including metadata and an explicit end.
+    pub fn next_flat(&mut self) -> Result<FlatStreamBit> {
+        if let Some(word) = self.pending_words.pop_front() {
+            return Ok(FlatStreamBit::SubStreamBit(SubStreamBit::Word(word)));
+        }
         loop {
             let ev = self.er.next();
             match ev? {
+                XmlEvent::StartElement { ref name, .. } if self.in_meta => {
+                    // Any element inside <meta> is part of the path to a value
+                    self.meta_path.push(name.local_name.clone());
+                }
                 XmlEvent::StartElement { name, attributes , .. } => {
                     match name.local_name.as_str() {
                         "meta" => {
                             self.in_meta = true;
                         }
                         "s" => {
-                            self.sent_id = req_value(&attributes, "id")?.parse::<u64>()?;
+                            let sent_id = req_value(&attributes, "id")
+                                .and_then(|id| parse_id(id));
+                            match sent_id {
+                                Ok(sent_id) => {
+                                    self.sent_id = sent_id;
+                                    self.raw_word_id = 0;
+                                    self.skipping_sent = false;
+                                }
+                                Err(msg) => {
+                                    self.malformed("s", msg)?;
+                                    self.skipping_sent = true;
+                                    continue;
+                                }
+                            }
                             return Ok(
                                 FlatStreamBit::SubStreamBit(
                                     SubStreamBit::SentDelim(
@@ -228,38 +412,56 @@ impl<T: Read> OpenSubtitleStream<T> {
                                         })));
                         }
                         "time" => {
-                            let full_id = req_value(&attributes, "id")?;
-                            let (delim_type, num_id) = parse_time_id(full_id.as_str())?;
-                            let offset = parse_duration(req_value(&attributes, "value")?.as_str())?;
-                            return Ok(
-                                FlatStreamBit::SubStreamBit(
-                                    SubStreamBit::BlockDelim(
-                                        BlockDelim {
-                                            id: num_id,
-                                            offset: offset,
-                                            delim_type: delim_type,
-                                        })));
+                            match self.parse_time(&attributes) {
+                                Ok(block_delim) => {
+                                    return Ok(
+                                        FlatStreamBit::SubStreamBit(
+                                            SubStreamBit::BlockDelim(block_delim)));
+                                }
+                                Err(msg) => {
+                                    self.malformed("time", msg)?;
+                                }
+                            }
                         }
                         "w" => {
-                            let dot_word_id = req_value(&attributes, "id")?;
-                            let end_word_id = dot_word_id.split('.').next_back().unwrap();
-                            self.word_id = Some(end_word_id.parse::<u64>()?);
-                        }
-                        tag_name => {
-                            if self.in_meta {
-                                if self.meta_cat.is_some() {
-                                    self.meta_attr = Some(tag_name.to_owned())
-                                } else {
-                                    self.meta_cat = Some(tag_name.to_owned())
+                            let word_id = req_value(&attributes, "id").and_then(|dot_word_id| {
+                                parse_id(dot_word_id.split('.').next_back().unwrap())
+                            });
+                            match word_id {
+                                Ok(word_id) => {
+                                    self.word = Some(Word {
+                                        id: word_id,
+                                        word: String::new(),
+                                        lemma: annotation(&attributes, "lemma"),
+                                        upos: annotation(&attributes, "upos"),
+                                        xpos: annotation(&attributes, "xpos"),
+                                        feats: annotation(&attributes, "feats"),
+                                    });
+                                }
+                                Err(msg) => {
+                                    self.word = None;
+                                    self.malformed("w", msg)?;
                                 }
                             }
+                        }
+                        _ => {
                             // pass on unknown tag currently
                         }
                     }
                 }
+                XmlEvent::EndElement { ref name }
+                        if self.in_meta && !self.meta_path.is_empty() => {
+                    if self.meta_path.last() == Some(&name.local_name) {
+                        self.meta_path.pop();
+                    }
+                }
                 XmlEvent::EndElement { name } => {
                     match name.local_name.as_str() {
                         "s" => {
+                            if self.skipping_sent {
+                                self.skipping_sent = false;
+                                continue;
+                            }
                             return Ok(
                                 FlatStreamBit::SubStreamBit(
                                     SubStreamBit::SentDelim(
@@ -269,35 +471,41 @@ impl<T: Read> OpenSubtitleStream<T> {
                                         })));
                         }
                         "w" => {
-                            self.word_id = None;
+                            self.word = None;
                         }
                         "meta" => {
+                            self.in_meta = false;
                             let meta = mem::replace(&mut self.meta, BTreeMap::new());
-                            return Ok(FlatStreamBit::Meta(meta));
+                            return Ok(FlatStreamBit::Meta(Meta::from_raw(meta)));
                         }
-                        tag_name => {
-                            if self.in_meta {
-                                if self.meta_attr.as_ref().map(|s| s.as_str() == tag_name).unwrap_or(false) {
-                                    self.meta_attr = None
-                                } else if self.meta_cat.as_ref().map(|s| s.as_str() == tag_name).unwrap_or(false) {
-                                    self.meta_cat = None
-                                }
-                            }
+                        _ => {
                             // pass on unknown tag currently
                         }
                     }
                 }
                 XmlEvent::Characters(chars) => {
                     if self.in_meta {
-                        if let Some((attr, cat)) = both(self.meta_cat.as_ref(), self.meta_attr.as_ref()) {
-                            // XXX: Might not strictly need to copy cat here
-                            self.meta.insert((attr.to_owned(), cat.to_owned()), chars);
+                        if !self.meta_path.is_empty() {
+                            // Text broken up by comments or entities is joined back together
+                            self.meta.entry(self.meta_path.join("."))
+                                .or_insert_with(String::new)
+                                .push_str(&chars);
                         }
-                    } else if let Some(word_id) = self.word_id {
+                    } else if self.skipping_sent {
+                        // The words of a malformed sentence are skipped along with it
+                    } else if let Some(ref word) = self.word {
                         return Ok(
                             FlatStreamBit::SubStreamBit(
                                 SubStreamBit::Word(
-                                    Word { id: word_id, word: chars })));
+                                    Word { word: chars, ..word.clone() })));
+                    } else if self.tokenization == Tokenization::Raw {
+                        for token in tokenize(&chars) {
+                            self.raw_word_id += 1;
+                            self.pending_words.push_back(Word::new(self.raw_word_id, token));
+                        }
+                        if let Some(word) = self.pending_words.pop_front() {
+                            return Ok(FlatStreamBit::SubStreamBit(SubStreamBit::Word(word)));
+                        }
                     }
                 }
                 XmlEvent::EndDocument => {
@@ -309,5 +517,111 @@ impl<T: Read> OpenSubtitleStream<T> {
     }
 }
 
+/// Iterates over the subtitle without the metadata, which is kept for `meta()`. Stops for good
+/// after the end of the document or the first error.
+impl<T: Read> Iterator for OpenSubtitleStream<T> {
+    type Item = Result<SubStreamBit>;
+
+    fn next(&mut self) -> Option<Result<SubStreamBit>> {
+        while !self.finished {
+            match self.next_flat() {
+                Ok(FlatStreamBit::SubStreamBit(bit)) => return Some(Ok(bit)),
+                Ok(FlatStreamBit::Meta(meta)) => {
+                    self.read_meta = Some(meta);
+                }
+                Ok(FlatStreamBit::EndStream) => {
+                    self.finished = true;
+                }
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Any kind of subtitle file, for reading collections which mix them.
+pub trait SubtitleSource: Iterator<Item=Result<SubStreamBit>> {
+    /// The metadata read so far
+    fn meta(&self) -> Option<&Meta>;
+    /// Whether the format has metadata at all, which SRT and WebVTT don't
+    fn has_meta(&self) -> bool;
+    /// Problems which were worked around rather than returned as errors
+    fn warnings(&self) -> &[Malformed];
+}
+
+impl<T: Read> SubtitleSource for OpenSubtitleStream<T> {
+    fn meta(&self) -> Option<&Meta> {
+        self.read_meta.as_ref()
+    }
+
+    fn has_meta(&self) -> bool {
+        true
+    }
+
+    fn warnings(&self) -> &[Malformed] {
+        &self.warnings
+    }
+}
+
+impl SubtitleSource for CueStream {
+    fn meta(&self) -> Option<&Meta> {
+        CueStream::meta(self)
+    }
+
+    fn has_meta(&self) -> bool {
+        false
+    }
+
+    fn warnings(&self) -> &[Malformed] {
+        &self.warnings
+    }
+}
+
+/// The kinds of subtitle file `open_subtitle` reads, by how their names end.
+pub const SUBTITLE_EXTENSIONS: &'static [&'static str] = &[".xml.gz", ".xml", ".srt", ".vtt"];
+
+pub fn is_subtitle_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().to_str()
+        .map(|path| SUBTITLE_EXTENSIONS.iter().any(|ext| path.ends_with(ext)))
+        .unwrap_or(false)
+}
+
+/// Opens an OpenSubtitles `.xml.gz` or `.xml`, SRT or WebVTT file, going by its name. The
+/// tokenization only applies to OpenSubtitles files, since the others are always raw text.
+pub fn open_subtitle<P: AsRef<Path>>(path: P, mode: ParseMode, recovery: Recovery,
+                                     tokenization: Tokenization)
+        -> std::io::Result<Box<SubtitleSource>> {
+    let subf = BufReader::new(File::open(&path)?);
+    open_subtitle_reader(path, subf, mode, recovery, tokenization)
+}
+
+/// Like `open_subtitle`, but reads the file named by path from input, for files which aren't on
+/// disk like archive entries.
+pub fn open_subtitle_reader<P, R>(path: P, input: R, mode: ParseMode, recovery: Recovery,
+                                  tokenization: Tokenization)
+        -> std::io::Result<Box<SubtitleSource>>
+        where P: AsRef<Path>, R: Read + 'static {
+    let name = path.as_ref().to_string_lossy().into_owned();
+    if name.ends_with(".xml.gz") {
+        Ok(Box::new(OpenSubtitleStream::with_mode(GzDecoder::new(input)?, mode)
+            .with_recovery(recovery)
+            .with_tokenization(tokenization)))
+    } else if name.ends_with(".xml") {
+        Ok(Box::new(OpenSubtitleStream::with_mode(input, mode)
+            .with_recovery(recovery)
+            .with_tokenization(tokenization)))
+    } else if name.ends_with(".srt") {
+        Ok(Box::new(CueStream::new(input, CueFormat::Srt, mode, recovery)?))
+    } else if name.ends_with(".vtt") {
+        Ok(Box::new(CueStream::new(input, CueFormat::WebVtt, mode, recovery)?))
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                format!("not a subtitle file: {}", name)))
+    }
+}
+
 //fn parse(subtitle_stream: &Read) -> Iterator<DocumentBit> {
 //}
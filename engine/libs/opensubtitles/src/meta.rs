@@ -0,0 +1,197 @@
+//! Typed subtitle metadata.
+//!
+//! The `<meta>` block of an OpenSubtitles file looks like
+//!
+//! ```text
+//! <meta>
+//!   <conversion><tokens>7391</tokens><sentences>1039</sentences>...</conversion>
+//!   <subtitle><language>Finnish</language><rating>0.0</rating>...</subtitle>
+//!   <source><year>2002</year><duration>97</duration><original>Finnish</original>...</source>
+//! </meta>
+//! ```
+//!
+//! Every value is kept by the dotted path of its element, like `source.year`, however deeply it
+//! is nested. The values this crate knows about are also parsed into typed fields, which are left
+//! as None when a value is missing or doesn't parse.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use duration;
+
+/// Keys which have a typed field.
+const KNOWN_KEYS: &'static [&'static str] = &[
+    "source.original", "source.year", "source.genre", "source.duration", "source.imdb",
+    "subtitle.language", "subtitle.date", "subtitle.rating", "subtitle.machine_translated",
+    "subtitle.blocks", "subtitle.duration",
+    "conversion.tokens", "conversion.sentences", "conversion.encoding",
+];
+
+/// What the subtitle says about the movie.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMeta {
+    /// The original languages of the movie, like `Finnish` or `Finnish,Swedish`
+    pub original: Option<String>,
+    pub year: Option<u64>,
+    pub genre: Vec<String>,
+    /// Given in minutes
+    pub duration: Option<Duration>,
+    pub imdb_id: Option<u64>,
+}
+
+/// What the subtitle says about itself.
+#[derive(Clone, Debug, Default)]
+pub struct SubtitleMeta {
+    pub language: Option<String>,
+    pub date: Option<String>,
+    pub rating: Option<f64>,
+    pub machine_translated: Option<bool>,
+    pub blocks: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+/// What the conversion to XML says about it.
+#[derive(Clone, Debug, Default)]
+pub struct ConversionMeta {
+    pub tokens: Option<u64>,
+    pub sentences: Option<u64>,
+    pub encoding: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Meta {
+    pub source: SourceMeta,
+    pub subtitle: SubtitleMeta,
+    pub conversion: ConversionMeta,
+    /// Every value by dotted path, including those without a typed field
+    pub raw: BTreeMap<String, String>,
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" => Some(true),
+        "0" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_minutes(value: &str) -> Option<Duration> {
+    match value.parse::<u64>() {
+        Ok(minutes) => Some(Duration::from_secs(minutes * 60)),
+        Err(_) => duration::lax::parse_duration(value).ok(),
+    }
+}
+
+impl Meta {
+    /// Parses the typed fields out of values keyed by dotted path.
+    pub fn from_raw(raw: BTreeMap<String, String>) -> Meta {
+        let raw: BTreeMap<String, String> = raw.into_iter()
+            .map(|(key, value)| (key, value.trim().to_owned()))
+            .collect();
+        let mut meta = Meta::default();
+        {
+            let get = |key: &str| raw.get(key).map(|value| value.as_str())
+                .and_then(|value| if value.is_empty() { None } else { Some(value) });
+            let num = |key: &str| get(key).and_then(|value| value.parse::<u64>().ok());
+            meta.source = SourceMeta {
+                original: get("source.original").map(|value| value.to_owned()),
+                year: num("source.year"),
+                genre: get("source.genre")
+                    .map(|genre| genre.split(',')
+                        .map(|genre| genre.trim())
+                        .filter(|genre| !genre.is_empty())
+                        .map(|genre| genre.to_owned())
+                        .collect())
+                    .unwrap_or_else(Vec::new),
+                duration: get("source.duration").and_then(parse_minutes),
+                imdb_id: get("source.imdb")
+                    .and_then(|imdb_id| imdb_id.trim_left_matches("tt").parse::<u64>().ok()),
+            };
+            meta.subtitle = SubtitleMeta {
+                language: get("subtitle.language").map(|value| value.to_owned()),
+                date: get("subtitle.date").map(|value| value.to_owned()),
+                rating: get("subtitle.rating").and_then(|value| value.parse::<f64>().ok()),
+                machine_translated: get("subtitle.machine_translated").and_then(parse_bool),
+                blocks: num("subtitle.blocks"),
+                duration: get("subtitle.duration")
+                    .and_then(|value| duration::lax::parse_duration(value).ok()),
+            };
+            meta.conversion = ConversionMeta {
+                tokens: num("conversion.tokens"),
+                sentences: num("conversion.sentences"),
+                encoding: get("conversion.encoding").map(|value| value.to_owned()),
+            };
+        }
+        meta.raw = raw;
+        meta
+    }
+
+    /// The value at a dotted path like `source.country`, as written.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.raw.get(key).map(|value| value.as_str())
+    }
+
+    /// The values which have no typed field.
+    pub fn unknown<'a>(&'a self) -> Box<Iterator<Item=(&'a str, &'a str)> + 'a> {
+        Box::new(self.raw.iter()
+            .filter(|&(key, _)| !KNOWN_KEYS.contains(&key.as_str()))
+            .map(|(key, value)| (key.as_str(), value.as_str())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use super::Meta;
+
+    fn meta(values: &[(&str, &str)]) -> Meta {
+        Meta::from_raw(values.iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect::<BTreeMap<_, _>>())
+    }
+
+    #[test]
+    fn genre() {
+        assert_eq!(meta(&[("source.genre", " Comedy, Drama,,Romance ")]).source.genre,
+                   vec!["Comedy", "Drama", "Romance"]);
+        assert!(meta(&[("source.genre", " ")]).source.genre.is_empty());
+        assert!(meta(&[]).source.genre.is_empty());
+    }
+
+    #[test]
+    fn numbers() {
+        let parsed = meta(&[
+            ("source.year", " 2002\n"),
+            ("source.imdb", "tt0311519"),
+            ("source.duration", "97"),
+            ("subtitle.machine_translated", "0"),
+        ]);
+        assert_eq!(parsed.source.year, Some(2002));
+        assert_eq!(parsed.source.imdb_id, Some(311519));
+        assert_eq!(parsed.source.duration, Some(Duration::from_secs(97 * 60)));
+        assert_eq!(parsed.subtitle.machine_translated, Some(false));
+        assert_eq!(meta(&[("source.imdb", "311519")]).source.imdb_id, Some(311519));
+
+        let broken = meta(&[("source.year", "20O2"), ("source.imdb", "tt")]);
+        assert_eq!(broken.source.year, None);
+        assert_eq!(broken.source.imdb_id, None);
+        // Still kept as written
+        assert_eq!(broken.get("source.year"), Some("20O2"));
+    }
+
+    #[test]
+    fn get() {
+        let parsed = meta(&[
+            ("source.country", "Finland"),
+            ("conversion.corrected.words", "12"),
+            ("subtitle.language", "Finnish"),
+        ]);
+        assert_eq!(parsed.get("source.country"), Some("Finland"));
+        assert_eq!(parsed.get("conversion.corrected.words"), Some("12"));
+        assert_eq!(parsed.get("conversion.corrected"), None);
+        assert_eq!(parsed.get("country"), None);
+        assert_eq!(parsed.subtitle.language, Some("Finnish".to_owned()));
+        assert_eq!(parsed.unknown().collect::<Vec<_>>(),
+                   vec![("conversion.corrected.words", "12"), ("source.country", "Finland")]);
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::Duration;
+use itertools::Itertools;
+use super::{Result, SubStreamBit, Word, SentDelim, BlockDelim, DelimType};
+
+#[derive(Debug)]
+pub struct Sentence {
+    pub id: u64,
+    pub words: Vec<Word>,
+    /// When the subtitle block the sentence starts in is shown, if known
+    pub start: Option<Duration>,
+    /// When the last subtitle block the sentence is in is hidden, if known
+    pub end: Option<Duration>,
+}
+
+impl Sentence {
+    /// The words separated by spaces, as tokenized.
+    pub fn text(&self) -> String {
+        self.words.iter().map(|word| word.word.as_str()).join(" ")
+    }
+}
+
+/// Groups a stream of subtitle bits into sentences. Words outside any sentence are dropped.
+pub struct Sentences<I> {
+    bits: I,
+    /// The sentence being read
+    open: Option<Sentence>,
+    /// The start of the latest block, which a sentence starting now is shown from
+    block_start: Option<Duration>,
+    finished: bool,
+}
+
+impl<I> Sentences<I> where I: Iterator<Item=Result<SubStreamBit>> {
+    pub fn new(bits: I) -> Sentences<I> {
+        Sentences {
+            bits: bits,
+            open: None,
+            block_start: None,
+            finished: false,
+        }
+    }
+}
+
+impl<I> Iterator for Sentences<I> where I: Iterator<Item=Result<SubStreamBit>> {
+    type Item = Result<Sentence>;
+
+    fn next(&mut self) -> Option<Result<Sentence>> {
+        while !self.finished {
+            let bit = match self.bits.next() {
+                Some(Ok(bit)) => bit,
+                Some(Err(err)) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+                None => {
+                    // A sentence left open at the end is still returned
+                    self.finished = true;
+                    return self.open.take().map(Ok);
+                }
+            };
+            match bit {
+                SubStreamBit::SentDelim(SentDelim { id, delim_type: DelimType::Start }) => {
+                    let unclosed = self.open.take();
+                    self.open = Some(Sentence {
+                        id: id,
+                        words: vec![],
+                        start: self.block_start,
+                        end: None,
+                    });
+                    if unclosed.is_some() {
+                        return unclosed.map(Ok);
+                    }
+                }
+                SubStreamBit::SentDelim(SentDelim { delim_type: DelimType::End, .. }) => {
+                    if self.open.is_some() {
+                        return self.open.take().map(Ok);
+                    }
+                }
+                SubStreamBit::BlockDelim(BlockDelim { offset, delim_type, .. }) => {
+                    match delim_type {
+                        DelimType::Start => {
+                            self.block_start = Some(offset);
+                            if let Some(ref mut sentence) = self.open {
+                                // Times at the very start of a sentence belong to it
+                                if sentence.words.is_empty() {
+                                    sentence.start = Some(offset);
+                                }
+                            }
+                        }
+                        DelimType::End => {
+                            if let Some(ref mut sentence) = self.open {
+                                sentence.end = Some(offset);
+                            }
+                        }
+                    }
+                }
+                SubStreamBit::Word(word) => {
+                    if let Some(ref mut sentence) = self.open {
+                        sentence.words.push(word);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use {OpenSubtitleStream, Result, SubStreamBit, Word, SentDelim, DelimType};
+    use super::Sentences;
+
+    const DOCUMENT: &'static str = r#"<?xml version="1.0" encoding="utf-8"?>
+<document id="3213">
+  <s id="1">
+    <time id="T1S" value="00:00:01,000" />
+    <w id="1.1">Hei</w>
+    <w id="1.2">!</w>
+  </s>
+  <s id="2">
+    <w id="2.1">Mitä</w>
+    <time id="T1E" value="00:00:03,000" />
+    <time id="T2S" value="00:00:04,000" />
+    <w id="2.2">nyt</w>
+    <w id="2.3">?</w>
+    <time id="T2E" value="00:00:05,500" />
+  </s>
+  <s id="3">
+    <time id="T3S" value="00:00:07,000" />
+    <w id="3.1">Ei</w>
+    <w id="3.2">mitään</w>
+    <time id="T3E" value="00:00:08,000" />
+  </s>
+</document>
+"#;
+
+    fn millis(millis: u64) -> Option<Duration> {
+        Some(Duration::from_millis(millis))
+    }
+
+    #[test]
+    fn times() {
+        let mut stream = OpenSubtitleStream::new(DOCUMENT.as_bytes());
+        let sentences = stream.sentences()
+            .map(|sentence| {
+                let sentence = sentence.unwrap();
+                (sentence.id, sentence.text(), sentence.start, sentence.end)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sentences, vec![
+            (1, "Hei !".to_owned(), millis(1000), None),
+            // Starts in the first block and ends in the second
+            (2, "Mitä nyt ?".to_owned(), millis(1000), millis(5500)),
+            // The time before the first word is the start
+            (3, "Ei mitään".to_owned(), millis(7000), millis(8000)),
+        ]);
+    }
+
+    #[test]
+    fn unclosed() {
+        let start = |id| Ok(SubStreamBit::SentDelim(SentDelim {
+            id: id,
+            delim_type: DelimType::Start,
+        }));
+        let word = |id, word: &str| Ok(SubStreamBit::Word(Word::new(id, word.to_owned())));
+        let bits: Vec<Result<SubStreamBit>> = vec![
+            word(1, "Ohi"),
+            start(1), word(1, "Avoin"),
+            start(2), word(1, "Toinen"), word(2, "avoin"),
+        ];
+        let sentences = Sentences::new(bits.into_iter())
+            .map(|sentence| {
+                let sentence = sentence.unwrap();
+                (sentence.id, sentence.text())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sentences, vec![(1, "Avoin".to_owned()), (2, "Toinen avoin".to_owned())]);
+    }
+}
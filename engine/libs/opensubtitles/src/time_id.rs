@@ -23,20 +23,76 @@ pub enum ErrorKind {
 
 pub fn parse_time_id(time_id: &str) -> Result<(DelimType, u64)> {
     // Parse duration like T12S
-    if time_id.len() < 3 {
+    if time_id.chars().count() < 3 {
         bail!(ErrorKind::NotEnoughCharacters(()));
     }
-    let (time_marker, rest) = time_id.split_at(1);
-    if time_marker != "T" {
+    let mut chars = time_id.chars();
+    if chars.next() != Some('T') {
         bail!(ErrorKind::ExpectedBeginWithT(()));
     }
-    let (str_id, start_end_marker) = rest.split_at(rest.len() - 1);
-    let delim_type = match start_end_marker {
-        "S" => DelimType::Start,
-        "E" => DelimType::End,
-        _ => bail!(ErrorKind::ExpectedBeginWithT(()))
+    let delim_type = match chars.next_back() {
+        Some('S') => DelimType::Start,
+        Some('E') => DelimType::End,
+        _ => bail!(ErrorKind::ExpectedEndWithSOrE(()))
     };
-    let num_id = str_id.parse::<u64>()?;
+    let num_id = chars.as_str().parse::<u64>()?;
     Ok((delim_type, num_id))
 }
 
+pub mod lax {
+    use super::{ErrorKind, Result, parse_time_id as parse_strict};
+    use DelimType;
+
+    pub fn parse_time_id(time_id: &str) -> Result<(DelimType, u64)> {
+        // Parse broken time ids like t12s, T 12 S and T12.5S, keeping only the number before any
+        // dot
+        let time_id = time_id.chars()
+            .filter(|c| !c.is_whitespace())
+            .flat_map(|c| c.to_uppercase())
+            .collect::<String>();
+        if time_id.chars().count() < 3 {
+            bail!(ErrorKind::NotEnoughCharacters(()));
+        }
+        // Any character can be broken, so split where the last one starts rather than a byte
+        // before the end
+        let (marker_idx, _) = time_id.char_indices().last().unwrap();
+        let (rest, start_end_marker) = time_id.split_at(marker_idx);
+        let num_id = rest.split('.').next().unwrap();
+        parse_strict(&format!("{}{}", num_id, start_end_marker))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use DelimType;
+    use super::{lax, parse_time_id};
+
+    #[test]
+    fn time_ids() {
+        assert_eq!(parse_time_id("T12S").ok(), Some((DelimType::Start, 12)));
+        assert_eq!(parse_time_id("T3E").ok(), Some((DelimType::End, 3)));
+    }
+
+    #[test]
+    fn broken_time_ids() {
+        // These reach the strict parser when skipping malformed elements, so must be errors
+        for time_id in &["", "TS", "T12", "t12s", "T12.5S", "ÄS", "ÄÄÄ", "TÄS", "T12Ä", "Ä12S"] {
+            assert!(parse_time_id(time_id).is_err(), "{} parsed", time_id);
+        }
+    }
+
+    #[test]
+    fn lax_time_ids() {
+        assert_eq!(lax::parse_time_id("T12S").ok(), Some((DelimType::Start, 12)));
+        assert_eq!(lax::parse_time_id("t12e").ok(), Some((DelimType::End, 12)));
+        assert_eq!(lax::parse_time_id("T 12 S").ok(), Some((DelimType::Start, 12)));
+        assert_eq!(lax::parse_time_id("T12.5E").ok(), Some((DelimType::End, 12)));
+    }
+
+    #[test]
+    fn lax_broken_time_ids() {
+        for time_id in &["", "TS", "T12", "12S", "TxS", "T12Ä", "T1ÄS", "ÄÄ"] {
+            assert!(lax::parse_time_id(time_id).is_err(), "{} parsed", time_id);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! A tokenizer for untokenized subtitle text, aware of Finnish orthography.
+//!
+//! Text is split on whitespace and then punctuation is split off the ends of each word, with runs
+//! of the same mark like `...` kept together. What is inside a word stays with it, so inflected
+//! abbreviations and numbers like `EU:n`, `USA:ssa` and `3,5`, compounds like `kuu-ukko` and
+//! words like `vaa'an` are single tokens. Common abbreviations like `esim.` and `jne.`, and
+//! initials, keep their full stop, and the first half of a coordinated compound like `ala-` in
+//! `ala- ja yläkoulu` keeps its hyphen. A hyphen with no word after it, like that of a cut off
+//! `en-`, is split off.
+
+/// Lowercased Finnish abbreviations written with a full stop.
+const ABBREVIATIONS: &'static [&'static str] = &[
+    "esim", "jne", "ym", "yms", "ns", "ts", "tms", "vrt", "ks", "huom", "kpl", "klo",
+    "n", "s", "v", "prof", "tri", "toht", "milj", "mrd", "mk", "eaa", "jaa", "ko", "ao", "em",
+    "nk", "yht", "os", "p", "puh", "vs", "ent", "ed",
+];
+
+fn is_abbreviation(word: &str) -> bool {
+    let mut chars = word.chars();
+    let is_initial = match (chars.next(), chars.next()) {
+        (Some(c), None) => c.is_uppercase(),
+        _ => false,
+    };
+    is_initial || ABBREVIATIONS.contains(&word.to_lowercase().as_str())
+}
+
+/// Adds punctuation as tokens, one per run of the same mark.
+fn push_punctuation(punctuation: &str, tokens: &mut Vec<String>) {
+    let mut run = String::new();
+    for c in punctuation.chars() {
+        if !run.is_empty() && !run.ends_with(c) {
+            tokens.push(run.clone());
+            run.clear();
+        }
+        run.push(c);
+    }
+    if !run.is_empty() {
+        tokens.push(run);
+    }
+}
+
+/// Adds the tokens of a chunk of text between whitespace, the next of which is `next`.
+fn push_chunk(chunk: &str, next: Option<&str>, tokens: &mut Vec<String>) {
+    let start = match chunk.find(|c: char| c.is_alphanumeric()) {
+        Some(start) => start,
+        None => {
+            push_punctuation(chunk, tokens);
+            return;
+        }
+    };
+    push_punctuation(&chunk[..start], tokens);
+    let rest = &chunk[start..];
+    // There is an alphanumeric character, so this finds one
+    let (last_idx, last) = rest.char_indices()
+        .filter(|&(_, c)| c.is_alphanumeric())
+        .last()
+        .unwrap();
+    let mut end = last_idx + last.len_utf8();
+    let word = &rest[..end];
+    let trailing = &rest[end..];
+    let word_follows = next.and_then(|next| next.chars().next())
+        .map_or(false, |c| c.is_alphanumeric());
+    let keeps_mark = (trailing.starts_with('.') && is_abbreviation(word)) ||
+        (trailing == "-" && word_follows && word.chars().all(|c| c.is_alphabetic()));
+    if keeps_mark {
+        // Both marks are a single byte
+        end += 1;
+    }
+    tokens.push(rest[..end].to_owned());
+    push_punctuation(&rest[end..], tokens);
+}
+
+/// Splits untokenized text into words and punctuation.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chunks = text.split_whitespace().peekable();
+    while let Some(chunk) = chunks.next() {
+        push_chunk(chunk, chunks.peek().cloned(), &mut tokens);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tokenize;
+
+    #[test]
+    fn punctuation_runs() {
+        assert_eq!(tokenize("Mitä?! Ei!!"), vec!["Mitä", "?", "!", "Ei", "!!"]);
+        assert_eq!(tokenize("(No, niin.)"), vec!["(", "No", ",", "niin", ".", ")"]);
+        assert_eq!(tokenize("3,5 % EU:n"), vec!["3,5", "%", "EU:n"]);
+    }
+
+    #[test]
+    fn abbreviations() {
+        assert_eq!(tokenize("esim. Jne. klo 5."), vec!["esim.", "Jne.", "klo", "5", "."]);
+        assert_eq!(tokenize("Mm. Hyvä."), vec!["Mm", ".", "Hyvä", "."]);
+        assert_eq!(tokenize("talo."), vec!["talo", "."]);
+    }
+
+    #[test]
+    fn initials() {
+        assert_eq!(tokenize("J. R. R. Tolkien"), vec!["J.", "R.", "R.", "Tolkien"]);
+        assert_eq!(tokenize("Se oli a."), vec!["Se", "oli", "a", "."]);
+    }
+
+    #[test]
+    fn hyphens() {
+        assert_eq!(tokenize("ala- ja yläkoulu"), vec!["ala-", "ja", "yläkoulu"]);
+        assert_eq!(tokenize("kuu-ukko"), vec!["kuu-ukko"]);
+        assert_eq!(tokenize("Mutta en-"), vec!["Mutta", "en", "-"]);
+        assert_eq!(tokenize("en- ..."), vec!["en", "-", "..."]);
+        assert_eq!(tokenize("- Hei"), vec!["-", "Hei"]);
+        assert_eq!(tokenize("A4- ja"), vec!["A4", "-", "ja"]);
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(tokenize("\"Hei\", hän sanoi."),
+                   vec!["\"", "Hei", "\"", ",", "hän", "sanoi", "."]);
+        assert_eq!(tokenize("vaa'an 'se'"), vec!["vaa'an", "'", "se", "'"]);
+    }
+
+    #[test]
+    fn ellipses() {
+        assert_eq!(tokenize("No... ehkä…"), vec!["No", "...", "ehkä", "…"]);
+        assert_eq!(tokenize("...ja sitten"), vec!["...", "ja", "sitten"]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Subtitle collections.
+//!
+//! A collection is either a directory tree of subtitle files or an archive of them, like the zip
+//! and tar.gz files OPUS distributes OpenSubtitles as, which can be read without extracting
+//! them. Subtitle files are named by their path relative to the directory or inside the archive.
+//!
+//! Archives can only be read through from the start, so the files wanted from them are read in
+//! batches, which are then parsed in parallel.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, Cursor};
+use std::mem;
+use std::path::{Path, PathBuf};
+use flate2::read::GzDecoder;
+use opensubtitles::{ParseMode, Recovery, SubtitleSource, Tokenization, open_subtitle,
+                    open_subtitle_reader, is_subtitle_path};
+use rayon::prelude::*;
+use tar;
+use walkdir::WalkDir;
+use zip::ZipArchive;
+use zip::result::ZipError;
+use invalid_data;
+
+/// How many files to read from an archive before parsing them.
+const ARCHIVE_BATCH: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+pub enum Collection {
+    Dir(PathBuf),
+    Archive(PathBuf, ArchiveKind),
+}
+
+/// How to read the subtitle files of a collection.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOptions {
+    pub mode: ParseMode,
+    pub recovery: Recovery,
+    pub tokenization: Tokenization,
+}
+
+fn zip_error(err: ZipError) -> io::Error {
+    match err {
+        ZipError::Io(err) => err,
+        err => invalid_data(err),
+    }
+}
+
+impl Collection {
+    /// A directory, or an archive going by its name: `.zip`, `.tar`, `.tar.gz` or `.tgz`.
+    pub fn open(path: &str) -> Result<Collection, String> {
+        let kind = if Path::new(path).is_dir() {
+            return Ok(Collection::Dir(PathBuf::from(path)));
+        } else if path.ends_with(".zip") {
+            ArchiveKind::Zip
+        } else if path.ends_with(".tar") {
+            ArchiveKind::Tar
+        } else if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            ArchiveKind::TarGz
+        } else if Path::new(path).exists() {
+            return Err(format!("{} is neither a directory nor a zip or tar archive", path));
+        } else {
+            return Err(format!("{} doesn't exist", path));
+        };
+        Ok(Collection::Archive(PathBuf::from(path), kind))
+    }
+
+    /// Calls f with the name and contents of each file in an archive.
+    fn for_each_entry<F>(path: &Path, kind: ArchiveKind, mut f: F) -> io::Result<()>
+            where F: FnMut(&Path, &mut Read) -> io::Result<()> {
+        match kind {
+            ArchiveKind::Zip => {
+                let mut archive = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+                for idx in 0..archive.len() {
+                    let mut entry = archive.by_index(idx).map_err(zip_error)?;
+                    let name = entry.name().to_owned();
+                    // Directories are entries too
+                    if name.ends_with('/') {
+                        continue;
+                    }
+                    f(Path::new(&name), &mut entry)?;
+                }
+            }
+            ArchiveKind::Tar | ArchiveKind::TarGz => {
+                let input = BufReader::new(File::open(path)?);
+                let input: Box<Read> = if kind == ArchiveKind::TarGz {
+                    Box::new(GzDecoder::new(input)?)
+                } else {
+                    Box::new(input)
+                };
+                let mut archive = tar::Archive::new(input);
+                for entry in archive.entries()? {
+                    let mut entry = entry?;
+                    if !entry.header().entry_type().is_file() {
+                        continue;
+                    }
+                    let name = entry.path()?.into_owned();
+                    f(&name, &mut entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of all the subtitle files, in order.
+    pub fn list(&self) -> io::Result<Vec<PathBuf>> {
+        let mut names = vec![];
+        match *self {
+            Collection::Dir(ref dir) => {
+                let entries = WalkDir::new(dir).into_iter()
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_type().is_file() &&
+                                    is_subtitle_path(entry.file_name()));
+                for entry in entries {
+                    names.push(entry.path().strip_prefix(dir).unwrap().to_owned());
+                }
+            }
+            Collection::Archive(ref path, kind) => {
+                Collection::for_each_entry(path, kind, |name, _| {
+                    if is_subtitle_path(name) {
+                        names.push(name.to_owned());
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Opens each of the named files and calls f with it, in parallel, returning what f returns
+    /// for each file. Files which can't be opened are passed on as errors.
+    pub fn read<R, F>(&self, names: &[PathBuf], options: ReadOptions, f: F)
+            -> io::Result<Vec<(PathBuf, R)>>
+            where R: Send, F: Fn(&Path, io::Result<Box<SubtitleSource>>) -> R + Sync {
+        match *self {
+            Collection::Dir(ref dir) => {
+                Ok(names.par_iter()
+                    .map(|name| {
+                        let ss = open_subtitle(dir.join(name), options.mode, options.recovery,
+                                               options.tokenization);
+                        (name.clone(), f(name, ss))
+                    })
+                    .collect())
+            }
+            Collection::Archive(ref path, kind) => {
+                let wanted: HashSet<&Path> = names.iter().map(|name| name.as_path()).collect();
+                let mut results = Vec::with_capacity(names.len());
+                let mut batch = vec![];
+                {
+                    let mut parse = |batch: Vec<(PathBuf, Vec<u8>)>| {
+                        let parsed: Vec<(PathBuf, R)> = batch.into_par_iter()
+                            .map(|(name, contents)| {
+                                let ss = open_subtitle_reader(&name, Cursor::new(contents),
+                                                              options.mode, options.recovery,
+                                                              options.tokenization);
+                                let result = f(&name, ss);
+                                (name, result)
+                            })
+                            .collect();
+                        results.extend(parsed);
+                    };
+                    Collection::for_each_entry(path, kind, |name, entry| {
+                        if wanted.contains(name) {
+                            let mut contents = vec![];
+                            entry.read_to_end(&mut contents)?;
+                            batch.push((name.to_owned(), contents));
+                            if batch.len() >= ARCHIVE_BATCH {
+                                parse(mem::replace(&mut batch, vec![]));
+                            }
+                        }
+                        Ok(())
+                    })?;
+                    parse(batch);
+                }
+                Ok(results)
+            }
+        }
+    }
+}
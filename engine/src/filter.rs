@@ -0,0 +1,450 @@
+//! Corpus filter expressions.
+//!
+//! `preindex` only indexes the documents a filter expression matches. Expressions test the
+//! subtitle metadata, addressed by dotted path like `source.year`, or the document id, addressed
+//! as `doc`:
+//!
+//! ```text
+//! source.original contains Finnish
+//! source.year >= 2000 and source.original contains Finnish
+//! source.year in 1990..1999 or source.genre contains Comedy
+//! subtitle.duration <= 1:45:00 and not subtitle.machine_translated = 1
+//! doc in @allow.txt and not doc in 311519,118694
+//! ```
+//!
+//! `contains`, `=` and `!=` compare text ignoring case, or numbers when both sides are numbers.
+//! `<`, `<=`, `>` and `>=` compare numbers. Durations like `1:45:00` or `01:38:07,100` count as
+//! numbers of seconds. `in` takes an inclusive range `low..high`, a comma separated list or
+//! `@file` naming a file with one value per line. Tests of metadata a document doesn't have are
+//! false. An empty expression matches everything.
+//!
+//! SRT and WebVTT files have no metadata at all, so metadata tests don't apply to them: they are
+//! matched unless the tests of `doc` alone rule them out, whatever the metadata would have been.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::iter::Peekable;
+use std::vec::IntoIter;
+use opensubtitles::Meta;
+
+
+#[derive(Debug)]
+enum Field {
+    Doc,
+    /// A dotted path into the metadata
+    Meta(String),
+}
+
+#[derive(Debug)]
+enum Test {
+    Contains(String),
+    Eq(String),
+    Ne(String),
+    Lt(f64),
+    Le(f64),
+    Gt(f64),
+    Ge(f64),
+    Range(f64, f64),
+    OneOf(Vec<String>),
+}
+
+#[derive(Debug)]
+enum Expr {
+    All,
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cond(Field, Test),
+}
+
+pub struct CorpusFilter {
+    text: String,
+    expr: Expr,
+}
+
+/// Reads a number, or a duration like 1:45:00 or 01:38:07,100 as seconds.
+fn number(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if let Ok(number) = value.parse::<f64>() {
+        return Some(number);
+    }
+    if !value.contains(':') {
+        return None;
+    }
+    let mut secs = 0.0;
+    for bit in value.split(':') {
+        let bit = bit.replace(',', ".");
+        match bit.parse::<f64>() {
+            Ok(bit) => secs = secs * 60.0 + bit,
+            Err(_) => return None,
+        }
+    }
+    Some(secs)
+}
+
+fn text_eq(a: &str, b: &str) -> bool {
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.trim().to_lowercase() == b.trim().to_lowercase(),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => return Err("unterminated quote in corpus filter".to_owned()),
+                }
+            }
+            tokens.push(token);
+        } else if c == '<' || c == '>' || c == '=' || c == '!' {
+            let mut token = c.to_string();
+            chars.next();
+            if chars.peek() == Some(&'=') {
+                token.push('=');
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || "()\"<>=!".contains(c) {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn read_values(values_fn: &str) -> Result<Vec<String>, String> {
+    let values_f = File::open(values_fn)
+        .map_err(|err| format!("can't open {}: {}", values_fn, err))?;
+    let mut values = vec![];
+    for line in BufReader::new(values_f).lines() {
+        let line = line.map_err(|err| format!("can't read {}: {}", values_fn, err))?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            values.push(line.to_owned());
+        }
+    }
+    Ok(values)
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<String>>,
+}
+
+impl Parser {
+    fn next(&mut self, expected: &str) -> Result<String, String> {
+        self.tokens.next().ok_or_else(|| format!("corpus filter ends where {} was expected",
+                                                 expected))
+    }
+
+    fn next_is(&mut self, token: &str) -> bool {
+        if self.tokens.peek().map(|next| next == token).unwrap_or(false) {
+            self.tokens.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and_expr()?;
+        while self.next_is("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary_expr()?;
+        while self.next_is("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary_expr(&mut self) -> Result<Expr, String> {
+        if self.next_is("not") {
+            return Ok(Expr::Not(Box::new(self.unary_expr()?)));
+        }
+        if self.next_is("(") {
+            let expr = self.or_expr()?;
+            if !self.next_is(")") {
+                return Err("missing ) in corpus filter".to_owned());
+            }
+            return Ok(expr);
+        }
+        self.cond()
+    }
+
+    fn cond(&mut self) -> Result<Expr, String> {
+        let field = self.next("a field")?;
+        let field = if field == "doc" {
+            Field::Doc
+        } else {
+            if field.contains('.') && field.split('.').all(|bit| !bit.is_empty()) {
+                Field::Meta(field)
+            } else {
+                return Err(format!("expected doc or a dotted metadata path but got '{}'", field));
+            }
+        };
+        let op = self.next("an operator")?;
+        let value = self.next("a value")?;
+        let num = || number(&value)
+            .ok_or_else(|| format!("expected a number after {} but got '{}'", op, value));
+        let test = match op.as_str() {
+            "contains" => Test::Contains(value.to_lowercase()),
+            "=" => Test::Eq(value.clone()),
+            "!=" => Test::Ne(value.clone()),
+            "<" => Test::Lt(num()?),
+            "<=" => Test::Le(num()?),
+            ">" => Test::Gt(num()?),
+            ">=" => Test::Ge(num()?),
+            "in" => {
+                if value.starts_with('@') {
+                    Test::OneOf(read_values(&value[1..])?)
+                } else if value.contains("..") {
+                    let mut bits = value.splitn(2, "..");
+                    match (bits.next().and_then(number), bits.next().and_then(number)) {
+                        (Some(low), Some(high)) => Test::Range(low, high),
+                        _ => return Err(format!("bad range '{}' in corpus filter", value)),
+                    }
+                } else {
+                    Test::OneOf(value.split(',').map(|bit| bit.trim().to_owned()).collect())
+                }
+            }
+            _ => return Err(format!("unknown operator '{}' in corpus filter", op)),
+        };
+        Ok(Expr::Cond(field, test))
+    }
+}
+
+impl Test {
+    fn matches(&self, value: &str) -> bool {
+        let num = || number(value);
+        match *self {
+            Test::Contains(ref needle) => value.to_lowercase().contains(needle.as_str()),
+            Test::Eq(ref other) => text_eq(value, other),
+            Test::Ne(ref other) => !text_eq(value, other),
+            Test::Lt(other) => num().map(|num| num < other).unwrap_or(false),
+            Test::Le(other) => num().map(|num| num <= other).unwrap_or(false),
+            Test::Gt(other) => num().map(|num| num > other).unwrap_or(false),
+            Test::Ge(other) => num().map(|num| num >= other).unwrap_or(false),
+            Test::Range(low, high) => num().map(|num| low <= num && num <= high).unwrap_or(false),
+            Test::OneOf(ref others) => others.iter().any(|other| text_eq(value, other)),
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, doc_id: u64, meta: &Meta) -> bool {
+        match *self {
+            Expr::All => true,
+            Expr::Not(ref expr) => !expr.matches(doc_id, meta),
+            Expr::And(ref a, ref b) => a.matches(doc_id, meta) && b.matches(doc_id, meta),
+            Expr::Or(ref a, ref b) => a.matches(doc_id, meta) || b.matches(doc_id, meta),
+            Expr::Cond(Field::Doc, ref test) => test.matches(&doc_id.to_string()),
+            Expr::Cond(Field::Meta(ref path), ref test) => {
+                meta.get(path)
+                    .map(|value| test.matches(value))
+                    .unwrap_or(false)
+            }
+        }
+    }
+
+    /// Whether the expression matches a document without metadata, or None when that depends
+    /// on the metadata.
+    fn matches_without_meta(&self, doc_id: u64) -> Option<bool> {
+        match *self {
+            Expr::All => Some(true),
+            Expr::Not(ref expr) => expr.matches_without_meta(doc_id).map(|matches| !matches),
+            Expr::And(ref a, ref b) => {
+                match (a.matches_without_meta(doc_id), b.matches_without_meta(doc_id)) {
+                    (Some(false), _) | (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                }
+            }
+            Expr::Or(ref a, ref b) => {
+                match (a.matches_without_meta(doc_id), b.matches_without_meta(doc_id)) {
+                    (Some(true), _) | (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                }
+            }
+            Expr::Cond(Field::Doc, ref test) => Some(test.matches(&doc_id.to_string())),
+            Expr::Cond(Field::Meta(_), _) => None,
+        }
+    }
+}
+
+impl CorpusFilter {
+    pub fn parse(text: &str) -> Result<CorpusFilter, String> {
+        let tokens = tokenize(text)?;
+        let expr = if tokens.is_empty() {
+            Expr::All
+        } else {
+            let mut parser = Parser { tokens: tokens.into_iter().peekable() };
+            let expr = parser.or_expr()?;
+            if let Some(token) = parser.tokens.next() {
+                return Err(format!("unexpected '{}' in corpus filter", token));
+            }
+            expr
+        };
+        Ok(CorpusFilter { text: text.trim().to_owned(), expr: expr })
+    }
+
+    /// The expression as given, for the manifest.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn matches(&self, doc_id: u64, meta: &Meta) -> bool {
+        self.expr.matches(doc_id, meta)
+    }
+
+    /// Matches a document in a format without metadata, ignoring the metadata tests.
+    pub fn matches_without_meta(&self, doc_id: u64) -> bool {
+        self.expr.matches_without_meta(doc_id).unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs::{File, remove_file};
+    use std::io::prelude::*;
+    use opensubtitles::Meta;
+    use super::{CorpusFilter, tokenize};
+
+    fn meta() -> Meta {
+        let raw: BTreeMap<String, String> = vec![
+            ("source.original", "Finnish"),
+            ("source.year", "2002"),
+            ("source.genre", "Comedy, Drama"),
+            ("subtitle.duration", "01:38:07,100"),
+            ("subtitle.machine_translated", "0"),
+        ].into_iter().map(|(key, value)| (key.to_owned(), value.to_owned())).collect();
+        Meta::from_raw(raw)
+    }
+
+    fn matches(expr: &str) -> bool {
+        CorpusFilter::parse(expr).unwrap().matches(311519, &meta())
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(tokenize("a.b<=3 and (c.d != \"Big Fish\")").unwrap(),
+                   vec!["a.b", "<=", "3", "and", "(", "c.d", "!=", "Big Fish", ")"]);
+        assert!(tokenize("a.b = \"Big Fish").is_err());
+    }
+
+    #[test]
+    fn comparisons() {
+        assert!(matches("source.original contains finn"));
+        assert!(matches("source.genre contains drama"));
+        assert!(matches("source.original = finnish"));
+        assert!(!matches("source.original != Finnish"));
+        assert!(matches("source.year = 2002.0"));
+        assert!(matches("source.year >= 2000"));
+        assert!(matches("source.year <= 2002"));
+        assert!(!matches("source.year < 2002"));
+        assert!(!matches("source.year > 2002"));
+        assert!(matches("subtitle.duration <= 1:45:00"));
+        assert!(!matches("subtitle.duration > 01:38:07,100"));
+        assert!(!matches("source.genre > 1"));
+    }
+
+    #[test]
+    fn in_tests() {
+        assert!(!matches("source.year in 1990..1999"));
+        assert!(matches("source.year in 2000..2002"));
+        assert!(matches("source.year in 1999,2002"));
+        assert!(matches("doc in 118694,311519"));
+        assert!(!matches("doc in 118694"));
+        let values_fn = env::temp_dir().join("corpus-filter-test-values.txt");
+        File::create(&values_fn).unwrap().write_all(b"# Allowed\n118694\n\n311519\n").unwrap();
+        let expr = format!("doc in @{}", values_fn.to_str().unwrap());
+        let filter = CorpusFilter::parse(&expr);
+        remove_file(&values_fn).unwrap();
+        let filter = filter.unwrap();
+        assert!(filter.matches(311519, &meta()));
+        assert!(!filter.matches(1, &meta()));
+        assert!(CorpusFilter::parse("doc in @/nonexistent/values.txt").is_err());
+    }
+
+    #[test]
+    fn missing_metadata() {
+        assert!(!matches("source.country = Finland"));
+        assert!(!matches("source.country != Finland"));
+        assert!(matches("not source.country = Finland"));
+        assert!(matches(""));
+        assert!(CorpusFilter::parse("  ").unwrap().matches(1, &Meta::default()));
+    }
+
+    #[test]
+    fn without_meta() {
+        let matches = |expr: &str, doc_id: u64| {
+            CorpusFilter::parse(expr).unwrap().matches_without_meta(doc_id)
+        };
+        assert!(matches("source.original contains Finnish", 1));
+        assert!(matches("not source.original contains Finnish", 1));
+        assert!(matches("doc = 1 and source.year > 2000", 1));
+        assert!(!matches("doc = 1 and source.year > 2000", 2));
+        assert!(!matches("source.year > 2000 and not doc = 2", 2));
+        assert!(matches("doc = 1 or source.year > 2000", 2));
+        assert!(matches("doc = 1 or source.year > 2000", 1));
+        assert!(!matches("doc = 1 or doc = 3", 2));
+        assert!(!matches("not (doc = 2 or source.year > 2000)", 2));
+        assert!(matches("not (doc = 1 and source.year > 2000)", 2));
+        assert!(matches("", 2));
+    }
+
+    #[test]
+    fn precedence() {
+        // and binds tighter than or, and not tighter than and
+        assert!(matches("source.year = 1990 and source.year = 1990 or source.year = 2002"));
+        assert!(!matches("source.year = 1990 and (source.year = 1990 or source.year = 2002)"));
+        assert!(matches("not source.year = 1990 and source.year = 2002"));
+        assert!(!matches("not (source.year = 1990 or source.year = 2002)"));
+        assert!(matches("not not source.year = 2002"));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |expr: &str| CorpusFilter::parse(expr).err().unwrap();
+        assert_eq!(error("source.year = 2002 source.year"),
+                   "unexpected 'source.year' in corpus filter");
+        assert_eq!(error("source.year = 2002 )"), "unexpected ')' in corpus filter");
+        assert_eq!(error("(source.year = 2002"), "missing ) in corpus filter");
+        assert_eq!(error("source.year"), "corpus filter ends where an operator was expected");
+        assert_eq!(error("source.year ="), "corpus filter ends where a value was expected");
+        assert_eq!(error("source.year ~ 2002"), "unknown operator '~' in corpus filter");
+        assert_eq!(error("year = 2002"),
+                   "expected doc or a dotted metadata path but got 'year'");
+        assert_eq!(error("source.year < soon"), "expected a number after < but got 'soon'");
+        assert_eq!(error("source.year in 1990..later"), "bad range '1990..later' in corpus filter");
+        assert_eq!(error("source.year = 2002 and"),
+                   "corpus filter ends where a field was expected");
+    }
+}
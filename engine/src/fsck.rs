@@ -0,0 +1,294 @@
+//! Index integrity checking.
+//!
+//! Checks an index on its own, and optionally against the term statistics and preindex it was
+//! built from. Problems are printed as they are found and counted rather than stopping at the
+//! first one.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::str;
+use fst::{Map, Streamer};
+use itertools::Itertools;
+use manifest::Manifest;
+use postings::{Posting, PostingsList, PostingsSource};
+use registry::Registry;
+use termstats::StatsReader;
+use PreindexReader;
+
+const MAX_REPORTED: usize = 100;
+
+struct Problems {
+    count: usize,
+}
+
+impl Problems {
+    fn report(&mut self, msg: String) {
+        self.count += 1;
+        if self.count <= MAX_REPORTED {
+            println!("Problem: {}", msg);
+        } else if self.count == MAX_REPORTED + 1 {
+            println!("Too many problems, only counting from now on");
+        }
+    }
+}
+
+fn check_postings(term: &str, postings: &PostingsList, known_docs: Option<&HashSet<u64>>,
+                  registry: Option<&Registry>, problems: &mut Problems) {
+    if let Some(pair) = postings.windows(2).find(|pair| pair[0] >= pair[1]) {
+        problems.report(format!("postings for {} out of order: {:?} then {:?}",
+                                term, pair[0], pair[1]));
+    }
+    if let Some(known_docs) = known_docs {
+        let unknown = postings.iter().find(|posting| !known_docs.contains(&posting.doc_idx));
+        if let Some(posting) = unknown {
+            problems.report(format!("postings for {} refer to unknown document {}",
+                                    term, posting.doc_idx));
+        }
+    }
+    if let Some(registry) = registry {
+        let unregistered = postings.iter().find(|posting| registry.get(posting.doc_idx).is_none());
+        if let Some(posting) = unregistered {
+            problems.report(format!("postings for {} refer to unregistered document {}",
+                                    term, posting.doc_idx));
+        }
+    }
+}
+
+fn check_term_stats(term: &str, postings: &PostingsList, term_stats: &StatsReader,
+                    problems: &mut Problems) {
+    let stats = match term_stats.term(term) {
+        Some(stats) => stats,
+        None => {
+            problems.report(format!("no statistics for {}", term));
+            return;
+        }
+    };
+    let doc_tfs = postings.iter()
+        .group_by(|posting| posting.doc_idx)
+        .into_iter()
+        .map(|(doc_idx, group)| (doc_idx, group.count() as u64))
+        .collect_vec();
+    let df = doc_tfs.len() as u64;
+    let cf = postings.len() as u64;
+    if stats.df != df || stats.cf != cf {
+        problems.report(format!("statistics for {} give df {} cf {} but postings give df {} cf {}",
+                                term, stats.df, stats.cf, df, cf));
+    }
+    for (doc_idx, tf) in doc_tfs {
+        let stored_tf = term_stats.tf(term, doc_idx);
+        if stored_tf != tf {
+            problems.report(format!("statistics for {} give tf {} in document {} but postings \
+                                     give {}", term, stored_tf, doc_idx, tf));
+            break;
+        }
+    }
+}
+
+fn check_index(map: &Map, source: &PostingsSource, lowercase: bool, registry: Option<&Registry>,
+               term_stats: Option<&StatsReader>, problems: &mut Problems) {
+    let known_docs: Option<HashSet<u64>> = term_stats.map(|term_stats| {
+        term_stats.docs().into_iter().map(|(doc_idx, _)| doc_idx).collect()
+    });
+    let mut stream = map.stream();
+    while let Some((term, value)) = stream.next() {
+        let term = match str::from_utf8(term) {
+            Ok(term) => term,
+            Err(_) => {
+                problems.report(format!("term {} isn't UTF-8", String::from_utf8_lossy(term)));
+                continue;
+            }
+        };
+        if lowercase && term.to_lowercase() != term {
+            problems.report(format!("term {} isn't lowercase but the manifest says it should be",
+                                    term));
+        }
+        let postings = match source.postings(value) {
+            Ok(postings) => postings,
+            Err(err) => {
+                problems.report(format!("postings for {}: {}", term, err));
+                continue;
+            }
+        };
+        check_postings(term, &postings, known_docs.as_ref(), registry, problems);
+        if let Some(term_stats) = term_stats {
+            check_term_stats(term, &postings, term_stats, problems);
+        }
+    }
+}
+
+/// What was recounted from the preindex.
+#[derive(Default)]
+struct PreindexCounts {
+    num_tokens: u64,
+    num_terms: u64,
+    num_indexed: u64,
+    num_stopped: u64,
+    doc_lens: BTreeMap<u64, u64>,
+    /// Every position, when lemmas are indexed at the same positions as words
+    positions: HashSet<(u64, u64, u64)>,
+}
+
+fn check_preindex_term(term: &str, postings: &PostingsList, map: &Map, source: &PostingsSource,
+                       counts: &mut PreindexCounts, problems: &mut Problems) {
+    counts.num_terms += 1;
+    match map.get(term) {
+        Some(value) => {
+            counts.num_indexed += 1;
+            // Any error here was already reported while checking the index
+            if let Ok(indexed) = source.postings(value) {
+                if &indexed != postings {
+                    problems.report(format!("postings for {} differ from the preindex", term));
+                }
+            }
+        }
+        None => {
+            counts.num_stopped += 1;
+        }
+    }
+}
+
+fn check_preindex(preindex_fn: &str, map: &Map, source: &PostingsSource, lemmas: bool,
+                  problems: &mut Problems) -> Option<PreindexCounts> {
+    let mut preindex = match File::open(preindex_fn) {
+        Ok(preindex) => preindex,
+        Err(err) => {
+            problems.report(format!("can't open preindex {}: {}", preindex_fn, err));
+            return None;
+        }
+    };
+    let mut counts = PreindexCounts::default();
+    let mut prev: Option<(String, u64, u64, u64)> = None;
+    let mut postings: PostingsList = vec![];
+    for (record_idx, record) in PreindexReader(&mut preindex).enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                problems.report(format!("preindex record {} can't be read: {:?}",
+                                        record_idx, err));
+                return None;
+            }
+        };
+        // Lemma records share the position of their word, which is only counted once
+        if lemmas {
+            counts.positions.insert((record.1, record.2, record.3));
+        } else {
+            counts.num_tokens += 1;
+            *counts.doc_lens.entry(record.1).or_insert(0) += 1;
+        }
+        if let Some(prev) = prev.take() {
+            if prev > record {
+                problems.report(format!("preindex record {} out of order: {:?} then {:?}",
+                                        record_idx, prev, record));
+            }
+            if prev.0 != record.0 {
+                check_preindex_term(&prev.0, &postings, map, source, &mut counts, problems);
+                postings.clear();
+            }
+        }
+        let (_, doc_idx, snt_idx, wrd_idx) = record;
+        postings.push(Posting { doc_idx: doc_idx, snt_idx: snt_idx, wrd_idx: wrd_idx });
+        prev = Some(record);
+    }
+    if let Some(prev) = prev {
+        check_preindex_term(&prev.0, &postings, map, source, &mut counts, problems);
+    }
+    counts.num_tokens += counts.positions.len() as u64;
+    for &(doc_idx, _, _) in &counts.positions {
+        *counts.doc_lens.entry(doc_idx).or_insert(0) += 1;
+    }
+    if counts.num_indexed != map.len() as u64 {
+        problems.report(format!("{} terms in the index aren't in the preindex",
+                                map.len() as u64 - counts.num_indexed));
+    }
+    Some(counts)
+}
+
+fn check_manifest(manifest: &Manifest, preindex_fn: Option<&str>,
+                  counts: Option<&PreindexCounts>, problems: &mut Problems) {
+    if let Some(preindex_fn) = preindex_fn {
+        match Manifest::load(preindex_fn) {
+            Ok(Some(preindex_manifest)) => {
+                if preindex_manifest.lowercase != manifest.lowercase ||
+                        preindex_manifest.tokenizer != manifest.tokenizer ||
+                        preindex_manifest.corpus_filter != manifest.corpus_filter {
+                    problems.report("index and preindex manifests disagree about normalization, \
+                                     tokenization or corpus filter".to_owned());
+                }
+            }
+            Ok(None) => problems.report(format!("preindex {} has no manifest", preindex_fn)),
+            Err(err) => problems.report(format!("preindex manifest: {}", err)),
+        }
+    }
+    if let Some(counts) = counts {
+        if manifest.num_docs != counts.doc_lens.len() as u64 {
+            problems.report(format!("manifest gives {} documents but the preindex has {}",
+                                    manifest.num_docs, counts.doc_lens.len()));
+        }
+        let num_stopped = manifest.options.get("num_stopped")
+            .and_then(|num_stopped| num_stopped.parse::<u64>().ok());
+        if let Some(num_stopped) = num_stopped {
+            if num_stopped != counts.num_stopped {
+                problems.report(format!("manifest gives {} stopwords but {} preindex terms \
+                                         aren't in the index", num_stopped, counts.num_stopped));
+            }
+        }
+    }
+}
+
+fn check_collection_stats(term_stats: &StatsReader, counts: &PreindexCounts,
+                          problems: &mut Problems) {
+    match term_stats.collection() {
+        Some(collection) => {
+            if collection.num_docs != counts.doc_lens.len() as u64 ||
+                    collection.num_tokens != counts.num_tokens ||
+                    collection.num_terms != counts.num_terms {
+                problems.report(format!(
+                    "collection statistics give {} documents, {} tokens and {} terms but the \
+                     preindex has {}, {} and {}",
+                    collection.num_docs, collection.num_tokens, collection.num_terms,
+                    counts.doc_lens.len(), counts.num_tokens, counts.num_terms));
+            }
+        }
+        None => problems.report("no collection statistics".to_owned()),
+    }
+    let stored_doc_lens: BTreeMap<u64, u64> = term_stats.docs().into_iter().collect();
+    if stored_doc_lens != counts.doc_lens {
+        problems.report("document lengths differ from the preindex".to_owned());
+    }
+}
+
+/// Checks the index and returns the number of problems found.
+pub fn fsck(map: &Map, source: &PostingsSource, manifest: Option<&Manifest>,
+            registry: Option<&Registry>, term_stats: Option<&StatsReader>,
+            preindex_fn: Option<&str>) -> usize {
+    let mut problems = Problems { count: 0 };
+    if manifest.is_none() {
+        problems.report("index has no manifest".to_owned());
+    }
+    match (manifest, registry) {
+        (_, None) => problems.report("index has no document registry".to_owned()),
+        (Some(manifest), Some(registry)) if registry.len() as u64 != manifest.num_docs => {
+            problems.report(format!("manifest gives {} documents but the registry has {}",
+                                    manifest.num_docs, registry.len()));
+        }
+        _ => {}
+    }
+    println!("Checking index");
+    check_index(map, source, manifest.map(|manifest| manifest.lowercase).unwrap_or(false),
+                registry, term_stats, &mut problems);
+    let counts = preindex_fn.and_then(|preindex_fn| {
+        println!("Checking index against preindex");
+        let lemmas = manifest
+            .and_then(|manifest| manifest.options.get("lemmas"))
+            .map(|lemmas| lemmas == "true")
+            .unwrap_or(false);
+        check_preindex(preindex_fn, map, source, lemmas, &mut problems)
+    });
+    if let Some(manifest) = manifest {
+        check_manifest(manifest, preindex_fn, counts.as_ref(), &mut problems);
+    }
+    if let (Some(term_stats), Some(counts)) = (term_stats, counts.as_ref()) {
+        check_collection_stats(term_stats, counts, &mut problems);
+    }
+    problems.count
+}
@@ -0,0 +1,144 @@
+//! Term inspection for the `lookup` subcommand.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::File;
+use fst::{Map, IntoStreamer, Streamer};
+use itertools::Itertools;
+use postings::{PostingsList, PostingsSource};
+use registry::Registry;
+use termstats::StatsReader;
+use {PreindexReader, preindex_record};
+
+pub struct LookupOptions<'a> {
+    /// A preindex to reconstruct the sentences the term occurs in from
+    pub contexts_fn: Option<&'a str>,
+    /// How many terms either side of the term in the index to list
+    pub neighbours: usize,
+    /// Whether to list the terms which start with the term
+    pub prefix: bool,
+    /// The most postings, contexts or prefix matches to list
+    pub limit: usize,
+}
+
+fn print_postings(term: &str, postings: &PostingsList, term_stats: Option<&StatsReader>,
+                  registry: Option<&Registry>, limit: usize) {
+    let df = postings.iter().map(|posting| posting.doc_idx).dedup().count();
+    println!("Postings df {} cf {}", df, postings.len());
+    if let Some(term_stats) = term_stats {
+        match term_stats.term(term) {
+            Some(stats) => println!("Statistics df {} cf {}", stats.df, stats.cf),
+            None => println!("Statistics missing"),
+        }
+    }
+    for posting in postings.iter().take(limit) {
+        print!("  doc {} sentence {} word {}", posting.doc_idx, posting.snt_idx, posting.wrd_idx);
+        if let Some(term_stats) = term_stats {
+            print!(" tf {}", term_stats.tf(term, posting.doc_idx));
+        }
+        match registry {
+            Some(registry) => println!(" ({})", registry.describe(posting.doc_idx)),
+            None => println!(""),
+        }
+    }
+    if postings.len() > limit {
+        println!("  ... and {} more", postings.len() - limit);
+    }
+}
+
+fn print_contexts(term: &str, postings: &PostingsList, contexts_fn: &str, limit: usize) {
+    // The preindex is sorted by term, so finding the rest of each sentence needs a full scan
+    let wanted: HashSet<(u64, u64)> = postings.iter()
+        .map(|posting| (posting.doc_idx, posting.snt_idx))
+        .unique()
+        .take(limit)
+        .collect();
+    let mut sentences: BTreeMap<(u64, u64), Vec<(u64, String)>> = BTreeMap::new();
+    let mut preindex = File::open(contexts_fn).unwrap();
+    for result in PreindexReader(&mut preindex) {
+        let (token, doc_idx, snt_idx, wrd_idx) = preindex_record(contexts_fn, result);
+        if wanted.contains(&(doc_idx, snt_idx)) {
+            sentences.entry((doc_idx, snt_idx)).or_insert_with(Vec::new).push((wrd_idx, token));
+        }
+    }
+    println!("Contexts:");
+    for (&(doc_idx, snt_idx), words) in &mut sentences {
+        words.sort();
+        let text = words.iter()
+            .map(|&(_, ref token)| if token == term {
+                format!("[{}]", token)
+            } else {
+                token.to_owned()
+            })
+            .join(" ");
+        println!("  {}:{} {}", doc_idx, snt_idx, text);
+    }
+}
+
+fn print_neighbours(map: &Map, term: &str, neighbours: usize) {
+    let mut before = VecDeque::with_capacity(neighbours + 1);
+    let mut stream = map.range().lt(term).into_stream();
+    while let Some((neighbour, _)) = stream.next() {
+        before.push_back(String::from_utf8_lossy(neighbour).into_owned());
+        if before.len() > neighbours {
+            before.pop_front();
+        }
+    }
+    println!("Neighbours:");
+    for neighbour in before {
+        println!("  {}", neighbour);
+    }
+    println!("  > {}", term);
+    let mut stream = map.range().gt(term).into_stream();
+    let mut num_after = 0;
+    while let Some((neighbour, _)) = stream.next() {
+        if num_after == neighbours {
+            break;
+        }
+        println!("  {}", String::from_utf8_lossy(neighbour));
+        num_after += 1;
+    }
+}
+
+fn print_prefixed(map: &Map, postings_source: &PostingsSource, term: &str, limit: usize) {
+    println!("Terms starting with {}:", term);
+    let mut stream = map.range().ge(term).into_stream();
+    let mut num_listed = 0;
+    while let Some((prefixed, value)) = stream.next() {
+        if !prefixed.starts_with(term.as_bytes()) {
+            break;
+        }
+        if num_listed == limit {
+            println!("  ...");
+            break;
+        }
+        let cf = postings_source.postings(value).map(|postings| postings.len()).unwrap_or(0);
+        println!("  {} value {} cf {}", String::from_utf8_lossy(prefixed), value, cf);
+        num_listed += 1;
+    }
+}
+
+pub fn lookup(map: &Map, postings_source: &PostingsSource, term_stats: Option<&StatsReader>,
+              registry: Option<&Registry>, term: &str, options: &LookupOptions) {
+    println!("Term {}", term);
+    match map.get(term) {
+        Some(value) => {
+            println!("FST value {}", value);
+            match postings_source.postings(value) {
+                Ok(postings) => {
+                    print_postings(term, &postings, term_stats, registry, options.limit);
+                    if let Some(contexts_fn) = options.contexts_fn {
+                        print_contexts(term, &postings, contexts_fn, options.limit);
+                    }
+                }
+                Err(err) => println!("Postings unreadable: {}", err),
+            }
+        }
+        None => println!("Not in index"),
+    }
+    if options.neighbours > 0 {
+        print_neighbours(map, term, options.neighbours);
+    }
+    if options.prefix {
+        print_prefixed(map, postings_source, term, options.limit);
+    }
+}
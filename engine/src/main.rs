@@ -8,69 +8,63 @@ extern crate xml;
 extern crate flate2;
 extern crate opensubtitles;
 extern crate walkdir;
+extern crate zip;
+extern crate tar;
 extern crate option_filter;
 extern crate rayon;
 //#[macro_use] extern crate cpp;
 extern crate fst_extra_aut as extra_aut;
 extern crate fst_levenshtein as levenshtein;
 
+mod postings;
+mod packed;
+mod termstats;
+mod manifest;
+mod fsck;
+mod stats;
+mod lookup;
+mod select;
+mod filter;
+mod registry;
+mod json;
+mod collection;
+mod translations;
+
 use std::error::Error;
-use std::fs::{File, remove_dir_all};
+use std::fs::{File, remove_dir_all, remove_file, rename};
 use std::io::prelude::*;
-use std::io::{BufWriter};
+use std::io::{BufReader, BufWriter};
 use std::io;
 use std::io::Error as IoError;
-use std::path::{Component, Path};
+use std::path::{Path, PathBuf};
 use fst::{MapBuilder, Map, IntoStreamer, Streamer};
 use fst::automaton::Automaton;
-use lmdb::{EnvBuilder, DbFlags};
-use lmdb::{ToMdbValue, FromMdbValue, MdbValue};
-use std::mem;
+use lmdb::{EnvBuilder, DbFlags, MdbError, MdbResult};
 use std::str;
-use std::slice;
 use std::hash::Hash;
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 use std::string::FromUtf8Error;
-use std::collections::{HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use itertools::Itertools;
-use opensubtitles::{OpenSubtitleStream, FlatStreamBit, Word, SentDelim, SubStreamBit, DelimType};
-use walkdir::{DirEntry, WalkDir};
+use opensubtitles::{Meta, ParseMode, Recovery, Sentences, Tokenization, Word};
 use option_filter::OptionFilterExt;
-use rayon::prelude::*;
 use extra_aut::levenshtein::unweighted::SimpleLevenshtein;
 use extra_aut::levenshtein::weighted::{mk_levenshtein, get_levenshtein_weights, LevenshteinStack};
 use extra_aut::hfst::{TransducerBox, mk_stack, get_weights, AutStack};
 use extra_aut::helpers::compare_weights;
-
-#[derive(Clone, Copy, Debug)]
-struct Posting {
-    doc_idx: u64,
-    snt_idx: u64,
-    wrd_idx: u64,
-}
-
-type PostingsList = Vec<Posting>;
-
-struct MdbPostingList<'a>(&'a [Posting]);
-
-impl<'b> ToMdbValue for MdbPostingList<'b> {
-    fn to_mdb_value<'a>(&'a self) -> MdbValue<'a> {
-        unsafe {
-            return MdbValue::new(std::mem::transmute(self.0.as_ptr()),
-                                 self.0.len() * mem::size_of::<u64>() * 3);
-        }
-    }
-}
-
-impl<'a> FromMdbValue for MdbPostingList<'a> {
-    fn from_mdb_value(value: &MdbValue) -> MdbPostingList<'a> {
-        unsafe {
-            let ptr = mem::transmute(value.get_ref());
-            return MdbPostingList(&slice::from_raw_parts(
-                ptr, value.get_size() / (mem::size_of::<u64>() * 3)));
-        }
-    }
-}
+use postings::{Posting, PostingsList, PostingsError, PostingsSource, term_key, encode_postings,
+               decode_postings_list};
+use packed::{PackedIndex, is_packed, pack};
+use termstats::{StatsReader, StatsWriter, TermStats, CollectionStats};
+use manifest::{Manifest, manifest_path, utc_now};
+use stats::IndexStats;
+use lookup::{LookupOptions, lookup};
+use json::{json_str, json_f64};
+use select::{SelectionPolicy, DEFAULT_POLICY};
+use filter::CorpusFilter;
+use registry::{Registry, registry_path, movie_key};
+use collection::{Collection, ReadOptions};
+use translations::{TranslationsReader, align};
 
 struct PreindexReader<'a>(&'a mut File);
 
@@ -118,6 +112,14 @@ impl<'a> Iterator for PreindexReader<'a> {
     }
 }
 
+fn preindex_record(preindex_fn: &str,
+                   result: Result<(String, u64, u64, u64), PreindexReaderError>)
+        -> (String, u64, u64, u64) {
+    result.unwrap_or_else(|err| {
+        fail(&format!("{} is corrupt ({:?}), check it with fsck --preindex", preindex_fn, err))
+    })
+}
+
 fn open_new(filename: &str) -> File {
     let path = Path::new(filename);
     let display = path.display();
@@ -130,43 +132,221 @@ fn open_new(filename: &str) -> File {
            };
 }
 
-fn del_if_exists(filename: &str) {
-    if !Path::new(filename).exists() {
-        return;
+fn invalid_data<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn remove_if_exists(filename: &str) {
+    let path = Path::new(filename);
+    if path.is_dir() {
+        remove_dir_all(path).unwrap();
+    } else if path.exists() {
+        remove_file(path).unwrap();
     }
+}
 
-    println!("Removing existing file {}", filename);
-    remove_dir_all(filename).unwrap();
+#[derive(Clone, Copy, PartialEq)]
+enum OutputKind {
+    File,
+    Lmdb,
 }
 
-fn get_env(db_fn: &str) -> lmdb::Environment {
+/// An output which is built at a temporary sibling path and only moved over its real path once
+/// it is complete, so a failed build leaves any existing output alone.
+struct Staged {
+    path: String,
+    tmp: String,
+    kind: OutputKind,
+}
+
+impl Staged {
+    fn new(path: &str, kind: OutputKind, force: bool) -> Staged {
+        let existing = Path::new(path);
+        if existing.exists() {
+            if !force {
+                fail(&format!("{} already exists, use --force to replace it", path));
+            }
+            // Make sure a mistyped argument can't replace something which isn't an old output
+            let is_lmdb = existing.is_dir() && existing.join("data.mdb").exists();
+            let right_kind = match kind {
+                OutputKind::File => existing.is_file(),
+                OutputKind::Lmdb => is_lmdb,
+            };
+            if !right_kind {
+                fail(&format!("{} exists but doesn't look like an output of this kind, \
+                               refusing to replace it", path));
+            }
+        }
+        let tmp = format!("{}.partial", path);
+        // Left over from an interrupted build
+        remove_if_exists(&tmp);
+        remove_if_exists(&manifest_path(&tmp));
+        remove_if_exists(&registry_path(&tmp));
+        Staged {
+            path: path.to_owned(),
+            tmp: tmp,
+            kind: kind,
+        }
+    }
+
+    fn tmp(&self) -> &str {
+        &self.tmp
+    }
+
+    fn commit(self) {
+        if self.kind == OutputKind::Lmdb && Path::new(&self.path).exists() {
+            // Directories can't be renamed over each other, so move the old one aside first
+            let old = format!("{}.old", self.path);
+            remove_if_exists(&old);
+            rename(&self.path, &old).unwrap();
+            rename(&self.tmp, &self.path).unwrap();
+            remove_dir_all(&old).unwrap();
+        } else {
+            rename(&self.tmp, &self.path).unwrap();
+        }
+        let sidecar_paths: [fn(&str) -> String; 2] = [manifest_path, registry_path];
+        for sidecar_path in &sidecar_paths {
+            let tmp_sidecar = sidecar_path(&self.tmp);
+            if Path::new(&tmp_sidecar).exists() {
+                rename(&tmp_sidecar, sidecar_path(&self.path)).unwrap();
+            } else {
+                // One left by an earlier build would describe that build rather than this one
+                remove_if_exists(&sidecar_path(&self.path));
+            }
+        }
+    }
+}
+
+const DEFAULT_MAP_SIZE: usize = 1_000_000_000; // 1gb
+// lmdb-rs doesn't give MDB_MAP_FULL its own MdbError variant, so match on the code from lmdb.h
+const MDB_MAP_FULL: i32 = -30792;
+
+fn get_env(db_fn: &str, map_size: usize) -> lmdb::Environment {
     let env = EnvBuilder::new().open(db_fn, 0o777).unwrap();
-    env.set_mapsize(1_000_000_000).unwrap(); // 1gb
+    // LMDB silently raises the map size to fit an existing database, so this only limits writers
+    env.set_mapsize(map_size).unwrap();
     env
 }
 
-fn new_db_txn<F>(db_fn: &str, cb: F)
-        where F: FnOnce(&lmdb::Transaction, &lmdb::Database) {
-    del_if_exists(db_fn);
-    let env = get_env(db_fn);
+fn new_db_txn<F>(db_fn: &str, map_size: usize, mut cb: F)
+        where F: FnMut(&lmdb::Transaction, &lmdb::Database) -> MdbResult<()> {
+    // Runs cb in a write transaction. If the map fills up, the transaction is aborted, the map
+    // size doubled and cb run again from scratch.
+    let env = get_env(db_fn, map_size);
     let db_handle = (&env).get_default_db(DbFlags::empty()).unwrap();
-    let txn = (&env).new_transaction().unwrap();
-    {
-        let db = txn.bind(&db_handle);
-        cb(&txn, &db);
+    let mut map_size = map_size;
+    loop {
+        let txn = (&env).new_transaction().unwrap();
+        let result = {
+            let db = txn.bind(&db_handle);
+            cb(&txn, &db)
+        };
+        let result = match result {
+            Ok(()) => txn.commit(),
+            Err(err) => {
+                // The map size can't be changed while a write transaction is open
+                txn.abort();
+                Err(err)
+            }
+        };
+        match result {
+            Ok(()) => return,
+            Err(MdbError::Other(MDB_MAP_FULL, _)) => {
+                map_size *= 2;
+                println!("LMDB map full for {}, retrying with map size {}", db_fn, map_size);
+                env.set_mapsize(map_size).unwrap();
+            }
+            Err(err) => panic!("Error writing {}: {}", db_fn, err),
+        }
     }
-    txn.commit().unwrap();
 }
 
 fn db_rdr<F>(db_fn: &str, cb: F)
         where F: FnOnce(&lmdb::ReadonlyTransaction, &lmdb::Database) {
-    let env = get_env(db_fn);
+    let env = get_env(db_fn, DEFAULT_MAP_SIZE);
     let db_handle = env.get_default_db(DbFlags::empty()).unwrap();
     let rdr = env.get_reader().unwrap();
     let db = rdr.bind(&db_handle);
     cb(&rdr, &db);
 }
 
+impl<'a> PostingsSource for lmdb::Database<'a> {
+    fn postings(&self, value: u64) -> Result<PostingsList, PostingsError> {
+        match self.get::<&[u8]>(&&term_key(value)[..]) {
+            Ok(bytes) => decode_postings_list(bytes),
+            Err(lmdb::MdbError::NotFound) => Err(PostingsError::Missing(value)),
+            Err(err) => panic!("Error reading postings: {}", err),
+        }
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    writeln!(&mut std::io::stderr(), "Error: {}", msg).unwrap();
+    std::process::exit(1);
+}
+
+fn with_index<F>(index_fn: &str, postings_fn: Option<&str>, cb: F)
+        where F: FnOnce(&Map, &PostingsSource, Option<&Manifest>, Option<&Registry>) {
+    // Opens either a packed index or an FST index along with its postings database
+    if is_packed(index_fn) {
+        let index = PackedIndex::open(index_fn).unwrap();
+        let manifest = if index.meta.contains_key("schema_version") {
+            Some(Manifest::from_map(index.meta.clone())
+                .unwrap_or_else(|err| fail(&format!("{}: {}", index_fn, err))))
+        } else {
+            None
+        };
+        cb(index.map(), &index, manifest.as_ref(), index.registry.as_ref());
+    } else {
+        let manifest = Manifest::load(index_fn)
+            .unwrap_or_else(|err| fail(&format!("{}: {}", index_fn, err)));
+        let registry = Registry::load(index_fn)
+            .unwrap_or_else(|err| fail(&format!("{}: {}", index_fn, err)));
+        let map = Map::from_path(index_fn).unwrap();
+        let postings_fn = postings_fn.unwrap_or_else(|| {
+            fail(&format!("{} isn't packed, so its postings must be given with --postings",
+                          index_fn))
+        });
+        db_rdr(postings_fn, |_postings_rdr, postings_db| {
+            cb(&map, postings_db, manifest.as_ref(), registry.as_ref())
+        });
+    }
+}
+
+fn query_lowercase(manifest: Option<&Manifest>, requested: bool) -> bool {
+    // Normalizes queries like the index was, falling back on what was asked for when there's no
+    // manifest to go on
+    match manifest {
+        Some(manifest) => manifest.query_lowercase(requested).unwrap_or_else(|err| fail(&err)),
+        None => {
+            writeln!(&mut std::io::stderr(),
+                     "Warning: index has no manifest, so queries are only lowercased if asked")
+                .unwrap();
+            requested
+        }
+    }
+}
+
+fn with_stats<F>(stats_fn: Option<&str>, cb: F)
+        where F: FnOnce(Option<&StatsReader>) {
+    match stats_fn {
+        Some(stats_fn) => db_rdr(stats_fn, |_stats_rdr, stats_db| {
+            cb(Some(&StatsReader(stats_db)))
+        }),
+        None => cb(None),
+    }
+}
+
+fn with_translations<F>(translations_fn: Option<&str>, cb: F)
+        where F: FnOnce(Option<&TranslationsReader>) {
+    match translations_fn {
+        Some(translations_fn) => db_rdr(translations_fn, |_translations_rdr, translations_db| {
+            cb(Some(&TranslationsReader(translations_db)))
+        }),
+        None => cb(None),
+    }
+}
+
 fn tokenize<'a>(line: &'a str, lowercase: bool)
         -> std::iter::Map<std::str::Split<'a, char>, fn(&str) -> String> {
     fn lower_token(token: &str) -> String {
@@ -180,129 +360,122 @@ fn tokenize<'a>(line: &'a str, lowercase: bool)
         .map(if lowercase { lower_token } else { own_token });
 }
 
-/*
-struct Meta {
-
+fn read_or_fail<T>(collection_dir: &str, result: io::Result<T>) -> T {
+    result.unwrap_or_else(|err| fail(&format!("can't read {}: {}", collection_dir, err)))
 }
 
-struct Sentence {
-    id: u64,
-    words: Vec<Token>,
-}
+const TOKENIZER: &'static str = "opensubtitles-w";
+const RAW_TOKENIZER: &'static str = "opensubtitles-raw-fi";
+const CORPUS_FILTER: &'static str = "source.original contains Finnish";
 
-enum Token {
-    Word {
-        id: u64,
-        word: String,
-    },
-    Time {
-        id: u64,
-        is_end: bool,
-        offset: Duration,
-    }
-}
-*/
-
-fn entry_is_subtitle(entry: &DirEntry) -> bool {
-    entry.file_type().is_file() &&
-        entry.file_name().to_str().map(|s| s.ends_with(".xml.gz")).unwrap_or(false)
-}
-
-fn id_of_path(path: &Path) -> Option<u64> {
-    let mut components = path.components();
-    components.next_back();
-    components.next_back().and_then(|comp|
-        match comp {
-            Component::Normal(path) => Some(path),
-            _ => None
-        })
-        .and_then(|path| path.to_str())
-        .and_then(|path| path.parse::<u64>().ok())
-}
-
-fn preindex(collection_dir: &str, preindex_fn: &str, tdf_fn: &str, lowercase: bool) {
-    /// Takes three file paths. Extracts tokens from xml files collection_dir, sorts in-memory and
-    /// writes preliminary index to preindex_fn. Documents are writen to docs_fn.
+fn preindex(collection_dir: &str, preindex_fn: &str, stats_fn: &str, lowercase: bool,
+            lemmas: bool, tokenization: Tokenization, corpus_filter: &CorpusFilter,
+            policy: &SelectionPolicy, strict: bool, map_size: usize, force: bool) {
+    /// Takes three file paths. Extracts tokens from subtitle files in collection_dir, which can
+    /// also be an archive, sorts in-memory and writes preliminary index to preindex_fn. Term and
+    /// document statistics are written to stats_fn. With lemmas, the lemma of each word is
+    /// indexed at the same position as the word itself where it differs.
+    let preindex_out = Staged::new(preindex_fn, OutputKind::File, force);
+    let stats_out = Staged::new(stats_fn, OutputKind::Lmdb, force);
 
     // read in collection
-    let walker = WalkDir::new(collection_dir).into_iter();
-    println!("Collection dir {}", collection_dir);
-    let mut seen = HashSet::new();
-    let subtitles = walker
-        .filter_map(|e| e.ok()
-        .filter(entry_is_subtitle))
-        .filter_map(|subtitle_entry| {
-            let subtitle_path = subtitle_entry.path();
-            let movie_id = id_of_path(subtitle_path).unwrap();
-            if seen.contains(&movie_id) {
-                None
-            } else {
-                seen.insert(movie_id);
-                Some((movie_id, subtitle_path.to_owned()))
-            }
-        }).collect_vec();
+    let collection = Collection::open(collection_dir).unwrap_or_else(|err| fail(&err));
+    println!("Collection {}", collection_dir);
+    let mut movies = BTreeMap::new();
+    for subtitle_path in read_or_fail(collection_dir, collection.list()) {
+        movies.entry(movie_key(&subtitle_path)).or_insert_with(Vec::new).push(subtitle_path);
+    }
+    println!("{} movies, choosing by {}", movies.len(), policy.describe());
+    let chosen = read_or_fail(collection_dir, policy.select(movies, &collection, tokenization));
+    let mut registry = Registry::assign(&chosen);
+    let doc_idxs: HashMap<PathBuf, u64> = registry.docs()
+        .map(|doc| (PathBuf::from(&doc.path), doc.doc_idx))
+        .collect();
+    let subtitles = doc_idxs.keys().cloned().sorted();
 
     println!("{} candidates", subtitles.len());
 
-    let mut lines: Vec<(String, u64, u64, u64)> =
-            subtitles.par_iter().flat_map(|&(ref movie_id, ref subtitle_path)| {
-        let mut ss = OpenSubtitleStream::from_path(subtitle_path).unwrap();
-        let mut should_use = false;
+    let (mode, recovery) = if strict {
+        (ParseMode::Strict, Recovery::Abort)
+    } else {
+        (ParseMode::Lax, Recovery::Skip)
+    };
+    let options = ReadOptions { mode: mode, recovery: recovery, tokenization: tokenization };
+    let docs = collection.read(&subtitles, options, |subtitle_path, ss| {
+        let doc_idx = doc_idxs[subtitle_path];
+        let mut ss = match ss {
+            Ok(ss) => ss,
+            Err(e) => {
+                println!("Skipping {}: {}", subtitle_path.to_string_lossy(), e);
+                return None;
+            }
+        };
         let mut new_lines = Vec::<(String, u64, u64)>::with_capacity(100);
-        let mut cur_sent_id = 0;
-        loop {
-            match ss.next(){
-                Ok(FlatStreamBit::SubStreamBit(bit)) => match bit {
-                    SubStreamBit::SentDelim(SentDelim { id, delim_type: DelimType::Start }) => {
-                        cur_sent_id = id;
-                    }
-                    SubStreamBit::SentDelim(SentDelim { id: _, delim_type: DelimType::End }) => {
-                    }
-                    SubStreamBit::Word(Word { id, word }) => {
-                        let norm_word = if lowercase {
-                            word.to_lowercase()
-                        } else {
-                            word
-                        };
-                        new_lines.push((norm_word, cur_sent_id, id));
-                    }
-                    _ => {}
-                },
-                Ok(FlatStreamBit::Meta(meta)) => {
-                    should_use = meta.get(&("source".to_owned(), "original".to_owned()))
-                        .map(|e| e.contains("Finnish"))
-                        .unwrap_or(false);
-                    if !should_use {
-                        break;
-                    }
-                }
-                Ok(FlatStreamBit::EndStream) => {
-                    break;
-                }
+        // Lemma lines are extra terms at the same positions, so the length only counts words
+        let mut doc_len = 0;
+        for sentence in Sentences::new(&mut ss) {
+            let sentence = match sentence {
+                Ok(sentence) => sentence,
                 Err(e) => {
                     println!("Skipping {}: {}", subtitle_path.to_string_lossy(), e.description());
-                    should_use = false;
-                    break;
+                    return None;
                 }
+            };
+            let normalize = |text: String| if lowercase { text.to_lowercase() } else { text };
+            for word in sentence.words {
+                let Word { id, word, lemma, .. } = word;
+                let norm_word = normalize(word);
+                if lemmas {
+                    if let Some(lemma) = lemma.map(&normalize).filter(|lemma| *lemma != norm_word) {
+                        new_lines.push((lemma, sentence.id, id));
+                    }
+                }
+                new_lines.push((norm_word, sentence.id, id));
+                doc_len += 1;
             }
         }
-        // XXX: Can boxing be avoided here?
-        if should_use {
-            new_lines.into_iter().sorted().into_iter()
-                    .map(|(word, snt_idx, wrd_idx)| (word, *movie_id, snt_idx, wrd_idx)).collect_vec().into_par_iter()
+        for warning in ss.warnings() {
+            println!("Warning: {}: {}", subtitle_path.to_string_lossy(), warning);
+        }
+        // Files without a <meta> block are filtered like ones with nothing in it
+        let meta = ss.meta().cloned().unwrap_or_default();
+        let matches = if ss.has_meta() {
+            corpus_filter.matches(doc_idx, &meta)
         } else {
-            vec![].into_par_iter()
+            corpus_filter.matches_without_meta(doc_idx)
+        };
+        if !matches {
+            println!("Skipping {}: doesn't match the corpus filter",
+                     subtitle_path.to_string_lossy());
+            return None;
         }
-    }).collect();
+        Some((doc_idx, meta, doc_len, new_lines))
+    });
+    let docs: Vec<(u64, Meta, u64, Vec<(String, u64, u64)>)> = read_or_fail(collection_dir, docs)
+        .into_iter()
+        .filter_map(|(_, doc)| doc)
+        .collect();
 
+    let mut lines: Vec<(String, u64, u64, u64)> = vec![];
+    let mut doc_lens: BTreeMap<u64, u64> = BTreeMap::new();
+    for (doc_idx, meta, doc_len, new_lines) in docs {
+        if new_lines.is_empty() {
+            continue;
+        }
+        registry.set_meta(doc_idx, &meta);
+        doc_lens.insert(doc_idx, doc_len);
+        lines.extend(new_lines.into_iter()
+            .map(|(word, snt_idx, wrd_idx)| (word, doc_idx, snt_idx, wrd_idx)));
+    }
     println!("{} lines", lines.len());
+    registry.retain(|doc_idx| doc_lens.contains_key(&doc_idx));
 
     println!("Sorting");
     // XXX: Not external and needs entire collection
     lines.sort();
 
     {
-        let mut outf = open_new(preindex_fn);
+        let mut outf = open_new(preindex_out.tmp());
         for &(ref term, doc_idx, snt_idx, wrd_idx) in &lines {
             // term
             outf.write_u64::<BigEndian>(term.len() as u64).unwrap();
@@ -316,145 +489,339 @@ fn preindex(collection_dir: &str, preindex_fn: &str, tdf_fn: &str, lowercase: bo
         }
     }
 
-    // count terms, group by term
-    new_db_txn(tdf_fn, |_txn, tdf_db| {
-        lines.iter()
-            .map(|&(ref token, doc_id, _, _)| (token, doc_id))
-            .group_by(|&(ref token, _doc_id)| token.to_owned()).into_iter()
-            .foreach(|(token, group)| {
-                let tdf = group.count() as u64;
-                tdf_db.set(&token.as_bytes(), &tdf).unwrap();
-            });
+    // count terms and documents
+    let num_docs = doc_lens.len() as u64;
+    new_db_txn(stats_out.tmp(), map_size, |_txn, stats_db| {
+        let stats_writer = StatsWriter(stats_db);
+        let mut num_terms = 0;
+        let term_groups = lines.iter()
+            .group_by(|&&(ref term, _, _, _)| term);
+        for (term, term_group) in term_groups.into_iter() {
+            let mut term_stats = TermStats::default();
+            let doc_groups = term_group.group_by(|&&(_, doc_idx, _, _)| doc_idx);
+            for (doc_idx, doc_group) in doc_groups.into_iter() {
+                let tf = doc_group.count() as u64;
+                term_stats.df += 1;
+                term_stats.cf += tf;
+                stats_writer.tf(term, doc_idx, tf)?;
+            }
+            stats_writer.term(term, &term_stats)?;
+            num_terms += 1;
+        }
+        for (&doc_idx, &doc_len) in &doc_lens {
+            stats_writer.doc_len(doc_idx, doc_len)?;
+        }
+        stats_writer.collection(&CollectionStats {
+            num_docs: num_docs,
+            num_tokens: doc_lens.values().sum(),
+            num_terms: num_terms,
+        })
     });
+
+    let tokenizer = match tokenization {
+        Tokenization::Words => TOKENIZER,
+        Tokenization::Raw => RAW_TOKENIZER,
+    };
+    let mut manifest = Manifest::new(lowercase, tokenizer, corpus_filter.text(), num_docs);
+    manifest.options.insert("collection".to_owned(), collection_dir.to_owned());
+    manifest.options.insert("selection".to_owned(), policy.describe());
+    manifest.options.insert("parsing".to_owned(),
+                            if strict { "strict" } else { "lax" }.to_owned());
+    if lemmas {
+        manifest.options.insert("lemmas".to_owned(), "true".to_owned());
+    }
+    manifest.save(preindex_out.tmp()).unwrap();
+    registry.save(preindex_out.tmp()).unwrap();
+    preindex_out.commit();
+    stats_out.commit();
 }
 
-fn fstindex(preindex_fn: &str, fstindex_fn: &str, postings_fn: &str) {
-    // input
+fn read_stopwords(stopwords_fn: &str) -> HashSet<String> {
+    // Reads a stopword file with one word per line. Blank lines and lines starting with # are
+    // ignored.
+    let stopwords_f = BufReader::new(File::open(stopwords_fn).unwrap());
+    stopwords_f.lines()
+        .map(|line| line.unwrap().trim().to_owned())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+fn auto_stoplist(preindex_fn: &str, max_df: f64) -> HashSet<String> {
+    // Makes a pass over the preindex and returns every term occurring in more than max_df
+    // documents. If max_df is below 1 it is taken as a proportion of all documents.
     let mut preindex = File::open(preindex_fn).unwrap();
-    // fst
-    let wtr = BufWriter::new(File::create(fstindex_fn).unwrap());
-    let mut map_builder = MapBuilder::new(wtr).unwrap();
+    let mut all_docs: HashSet<u64> = HashSet::new();
+    let mut dfs: Vec<(String, u64)> = vec![];
+    PreindexReader(&mut preindex)
+        .map(|result| preindex_record(preindex_fn, result))
+        .group_by(|&(ref term, _, _, _)| term.to_owned())
+        .into_iter()
+        .foreach(|(term, group)| {
+            // Records are sorted by document within a term
+            let df = group
+                .map(|(_, doc_idx, _, _)| {
+                    all_docs.insert(doc_idx);
+                    doc_idx
+                })
+                .dedup()
+                .count() as u64;
+            dfs.push((term, df));
+        });
+    let threshold = if max_df < 1.0 {
+        max_df * all_docs.len() as f64
+    } else {
+        max_df
+    };
+    println!("Stopping terms in more than {} of {} documents", threshold, all_docs.len());
+    dfs.into_iter()
+        .filter(|&(_, df)| df as f64 > threshold)
+        .map(|(term, _)| term)
+        .collect()
+}
+
+fn fstindex(preindex_fn: &str, fstindex_fn: &str, postings_fn: &str,
+            stopwords_fn: Option<&str>, max_df: Option<f64>, stoplist_out_fn: Option<&str>,
+            map_size: usize, force: bool) {
+    let fstindex_out = Staged::new(fstindex_fn, OutputKind::File, force);
+    let postings_out = Staged::new(postings_fn, OutputKind::Lmdb, force);
+    let stoplist_out = stoplist_out_fn.map(|stoplist_out_fn| {
+        Staged::new(stoplist_out_fn, OutputKind::File, force)
+    });
+    // stoplist
+    let mut stoplist = stopwords_fn.map(read_stopwords).unwrap_or_else(HashSet::new);
+    if let Some(max_df) = max_df {
+        stoplist.extend(auto_stoplist(preindex_fn, max_df));
+    }
+    println!("{} stopwords", stoplist.len());
+    // stopwords actually found in the preindex along with the number of postings dropped
+    let mut stopped: Vec<(String, usize)> = vec![];
     // set up postings db
-    new_db_txn(postings_fn, |_txn, postings_db| {
-        let reader = PreindexReader(&mut preindex);
-
-        reader
-            .map(|result| result.unwrap())
-            .group_by(|&(ref term, _, _, _)| term.to_owned())
-            .into_iter()
-            .enumerate()
-            .foreach(|(idx, (term, group))| {
-                let idx = idx as u64;
-                map_builder.insert(term.as_str(), idx).unwrap();
-                let postings : PostingsList =
-                    group.map(|(_, doc_idx, snt_idx, wrd_idx)|
-                        Posting {
-                            doc_idx: doc_idx,
-                            snt_idx: snt_idx,
-                            wrd_idx: wrd_idx
-                        }).collect();
-                postings_db.set(&idx, &MdbPostingList(&postings)).unwrap();
-            });
+    new_db_txn(postings_out.tmp(), map_size, |_txn, postings_db| {
+        // input
+        let mut preindex = File::open(preindex_fn).unwrap();
+        // fst
+        let wtr = BufWriter::new(File::create(fstindex_out.tmp()).unwrap());
+        let mut map_builder = MapBuilder::new(wtr).unwrap();
+        stopped.clear();
+        let groups = PreindexReader(&mut preindex)
+            .map(|result| preindex_record(preindex_fn, result))
+            .group_by(|&(ref term, _, _, _)| term.to_owned());
+        let mut idx = 0;
+
+        for (term, group) in groups.into_iter() {
+            if stoplist.contains(&term) {
+                stopped.push((term, group.count()));
+                continue;
+            }
+            map_builder.insert(term.as_str(), idx).unwrap();
+            let postings : PostingsList =
+                group.map(|(_, doc_idx, snt_idx, wrd_idx)|
+                    Posting {
+                        doc_idx: doc_idx,
+                        snt_idx: snt_idx,
+                        wrd_idx: wrd_idx
+                    }).collect();
+            postings_db.set(&&term_key(idx)[..], &encode_postings(&postings))?;
+            idx += 1;
+        }
+        map_builder.finish().unwrap();
+        Ok(())
     });
-    map_builder.finish().unwrap();
+    println!("Stopped {} terms with {} postings", stopped.len(),
+             stopped.iter().map(|&(_, count)| count).sum::<usize>());
+    match Manifest::load(preindex_fn).unwrap() {
+        Some(mut manifest) => {
+            manifest.built = utc_now();
+            if let Some(stopwords_fn) = stopwords_fn {
+                manifest.options.insert("stopwords".to_owned(), stopwords_fn.to_owned());
+            }
+            if let Some(max_df) = max_df {
+                manifest.options.insert("max_df".to_owned(), max_df.to_string());
+            }
+            manifest.options.insert("num_stopped".to_owned(), stopped.len().to_string());
+            manifest.save(fstindex_out.tmp()).unwrap();
+        }
+        None => {
+            println!("Warning: {} has no manifest so none will be written for {}",
+                     preindex_fn, fstindex_fn);
+        }
+    }
+    match Registry::load(preindex_fn).unwrap() {
+        Some(registry) => registry.save(fstindex_out.tmp()).unwrap(),
+        None => {
+            println!("Warning: {} has no document registry so none will be written for {}",
+                     preindex_fn, fstindex_fn);
+        }
+    }
+    if let Some(stoplist_out) = stoplist_out {
+        {
+            let mut outf = BufWriter::new(open_new(stoplist_out.tmp()));
+            for &(ref term, _) in &stopped {
+                writeln!(outf, "{}", term).unwrap();
+            }
+        }
+        stoplist_out.commit();
+    }
+    fstindex_out.commit();
+    postings_out.commit();
     println!("Done!");
 }
 
-fn stats(fstindex_fn: &str, postings_fn: &str) {
+fn pack_index(fstindex_fn: &str, postings_fn: &str, index_fn: &str, force: bool) {
+    let index_out = Staged::new(index_fn, OutputKind::File, force);
     let map = Map::from_path(fstindex_fn).unwrap();
-    println!("Size of dictionary: {}", map.len());
+    let mut meta = Manifest::load(fstindex_fn).unwrap()
+        .map(|manifest| manifest.to_map())
+        .unwrap_or_else(BTreeMap::new);
+    meta.insert("fstindex".to_owned(), fstindex_fn.to_owned());
+    meta.insert("postings".to_owned(), postings_fn.to_owned());
+    let registry = Registry::load(fstindex_fn).unwrap();
     db_rdr(postings_fn, |_postings_rdr, postings_db| {
-        let mut total_postings = 0;
-        let mut unique_docs : HashSet<u64> = HashSet::new();
-        for cur in postings_db.iter().unwrap() {
-            let postings = cur.get_value::<MdbPostingList>().0;
-            total_postings += postings.len();
-            unique_docs.extend(postings.into_iter()
-                .map(|&Posting {doc_idx, .. }| doc_idx));
-        }
-        println!("Done!");
-        println!("Total number of docs: {}", unique_docs.len());
-        println!("Total number of postings: {}", total_postings);
+        pack(&map, postings_db, &meta, registry.as_ref(), index_out.tmp()).unwrap();
     });
+    index_out.commit();
+    println!("Done!");
+}
+
+/// What the REPL prints about the documents matching each query.
+struct ReplOutput<'a, 'b: 'a> {
+    registry: Option<&'a Registry>,
+    /// The translations to show under each hit, if any
+    translations: Option<&'a TranslationsReader<'b>>,
+    /// How many of the best matching postings to show
+    num_hits: usize,
+    /// Print one JSON object per query instead of text
+    json: bool,
+}
+
+fn print_repl_json(query: &str, corrections: &[(f64, String)], hits: &[(String, f64, Posting)],
+                   output: &ReplOutput) {
+    let matches = corrections.iter()
+        .map(|&(weight, ref term)| format!("{{\"term\":{},\"weight\":{}}}",
+                                           json_str(term), json_f64(weight)))
+        .join(",");
+    let hits = hits.iter()
+        .map(|&(ref term, weight, Posting { doc_idx, snt_idx, wrd_idx })| {
+            let movie = output.registry
+                .and_then(|registry| registry.get(doc_idx))
+                .map(|doc| doc.to_json())
+                .unwrap_or_else(|| "null".to_owned());
+            let translation = output.translations
+                .and_then(|translations| translations.translation(doc_idx, snt_idx))
+                .map(|translation| json_str(&translation))
+                .unwrap_or_else(|| "null".to_owned());
+            format!("{{\"term\":{},\"weight\":{},\"doc\":{},\"snt\":{},\"wrd\":{},\
+                     \"movie\":{},\"translation\":{}}}",
+                    json_str(term), json_f64(weight), doc_idx, snt_idx, wrd_idx, movie,
+                    translation)
+        })
+        .join(",");
+    println!("{{\"query\":{},\"matches\":[{}],\"hits\":[{}]}}",
+             json_str(query), matches, hits);
 }
 
-fn repl<F, A, S, GW>(fstindex_fn: &str, postings_fn: &str, lowercase: bool,
-                     dump_file: Option<&str>, verbose: bool, mk_aut: F, get_weights: GW)
+fn repl<F, A, S, GW>(map: &Map, postings_source: &PostingsSource,
+                     term_stats: Option<&StatsReader>, lowercase: bool,
+                     dump_file: Option<&str>, verbose: bool, output: &ReplOutput, mk_aut: F,
+                     get_weights: GW)
         where F: Fn(&str) -> A,
               A: Automaton<State=S>,
               GW: Fn(&A, &[u8]) -> f64 {
-    // FST db
-    let map = Map::from_path(fstindex_fn).unwrap();
-    // Postings db
-    db_rdr(postings_fn, |_postings_rdr, postings_db| {
-        // get user input
-        let stdin = std::io::stdin();
-        let lock = stdin.lock();
-        for input in lock.lines() {
-            let input = input.unwrap();
-            // XXX: Should tokenize query properly (deal with punctuation)
-            // XXX: Copy here not strictly neccesary
-            let terms = tokenize(input.as_str(), lowercase).collect_vec();
-            if terms.len() == 0 {
+    // get user input
+    let stdin = std::io::stdin();
+    let lock = stdin.lock();
+    for input in lock.lines() {
+        let input = input.unwrap();
+        // XXX: Should tokenize query properly (deal with punctuation)
+        // XXX: Copy here not strictly neccesary
+        let terms = tokenize(input.as_str(), lowercase).collect_vec();
+        if terms.len() == 0 {
+            if output.json {
+                println!("{{\"query\":\"\",\"error\":\"Please enter at least one term!\"}}");
+            } else {
                 println!("Please enter at least one term!");
-                continue;
             }
-            let term = terms.concat();
+            continue;
+        }
+        let term = terms.concat();
+        if !output.json {
             println!("{}", term);
-            let mut docs: Vec<(String, f64, Posting)> = vec![];
-            let mut corrections: Vec<(f64, String)> = vec![];
-            /*
-            // XXX: write_in_att_format needs mut!
-            let mut fsa_inner = err_model.text_to_denoised_fsa(term.as_str()).unwrap();
-            if let Some(dump_file) = dump_file {
-                // trace - write out automaton to file to:
-                // * intersect with omorfi accceptor
-                // * get set of strings matched by automaton
-                // * see dot graph
-                fsa_inner.write_in_att_format(dump_file);
-            }
-            */
-            let fsa = mk_aut(term.as_str());
-            writeln!(&mut std::io::stderr(), "FSAs done").unwrap();
-            //let fsa = SimpleLevenshtein::new(term.as_str(), 1);
-            //let fsa = Levenshtein::new(term.as_str(), 1).unwrap();
-            //let fsa1 = mk_levenshtein(term.as_str(), 2.5, 8);
-            //let fsa2 = mk_levenshtein(term.as_str(), 2.5, 8);
-            let results = map.search(&fsa);
-            let mut results_stream = results.into_stream();
-            while let Some((corrected_term, posting_id)) = results_stream.next() {
-                let postings_list = postings_db
-                    .get::<MdbPostingList>(&posting_id)
-                    .unwrap().0.to_vec();
-                let weight = get_weights(&fsa, corrected_term);
-                let correct_term_str = String::from_utf8(corrected_term.to_owned()).unwrap();
-                //println!("{} {}", correct_term_str, weight);
-                corrections.push((weight, correct_term_str.to_owned()));
-                for &posting in postings_list.iter() {
-                    docs.push((
-                        correct_term_str.to_owned(),
-                        weight,
-                        posting));
-                }
+        }
+        let mut docs: Vec<(String, f64, Posting)> = vec![];
+        let mut corrections: Vec<(f64, String)> = vec![];
+        /*
+        // XXX: write_in_att_format needs mut!
+        let mut fsa_inner = err_model.text_to_denoised_fsa(term.as_str()).unwrap();
+        if let Some(dump_file) = dump_file {
+            // trace - write out automaton to file to:
+            // * intersect with omorfi accceptor
+            // * get set of strings matched by automaton
+            // * see dot graph
+            fsa_inner.write_in_att_format(dump_file);
+        }
+        */
+        let fsa = mk_aut(term.as_str());
+        writeln!(&mut std::io::stderr(), "FSAs done").unwrap();
+        //let fsa = SimpleLevenshtein::new(term.as_str(), 1);
+        //let fsa = Levenshtein::new(term.as_str(), 1).unwrap();
+        //let fsa1 = mk_levenshtein(term.as_str(), 2.5, 8);
+        //let fsa2 = mk_levenshtein(term.as_str(), 2.5, 8);
+        let results = map.search(&fsa);
+        let mut results_stream = results.into_stream();
+        while let Some((corrected_term, posting_id)) = results_stream.next() {
+            let postings_list = postings_source.postings(posting_id)
+                .unwrap_or_else(|err| panic!("Corrupt postings list for {}: {}",
+                                             String::from_utf8_lossy(corrected_term), err));
+            let weight = get_weights(&fsa, corrected_term);
+            let correct_term_str = String::from_utf8(corrected_term.to_owned()).unwrap();
+            //println!("{} {}", correct_term_str, weight);
+            corrections.push((weight, correct_term_str.to_owned()));
+            for &posting in postings_list.iter() {
+                docs.push((
+                    correct_term_str.to_owned(),
+                    weight,
+                    posting));
             }
-            // XXX: Process multiple terms here
-            if docs.len() == 0 {
-                println!("No results!");
+        }
+        // XXX: Process multiple terms here
+        corrections.sort_by(|&(ref w1, _), &(ref w2, _)| compare_weights(w1, w2));
+        docs.sort_by(|&(_, ref w1, ref p1), &(_, ref w2, ref p2)| {
+            compare_weights(w1, w2).then_with(|| p1.cmp(p2))
+        });
+        docs.truncate(output.num_hits);
+        if output.json {
+            print_repl_json(&term, &corrections, &docs, output);
+            continue;
+        }
+        if corrections.len() == 0 {
+            println!("No results!");
+            continue;
+        }
+        for &(weight, ref correct_term) in &corrections {
+            println!("Match {} {}", correct_term, weight);
+            if !verbose {
                 continue;
             }
-            corrections.sort_by(|&(ref w1, _), &(ref w2, _)| compare_weights(w1, w2));
-            for (weight, correct_term) in corrections {
-                println!("Match {} {}", correct_term, weight);
+            if let Some(stats) = term_stats.and_then(|term_stats| term_stats.term(correct_term)) {
+                writeln!(&mut std::io::stderr(), "{} df {} cf {}",
+                         correct_term, stats.df, stats.cf).unwrap();
             }
-            // Print results
-            for (correct_term, weight, Posting { doc_idx, snt_idx, wrd_idx }) in docs {
-                //println!("Match {} {} {} {} {}", correct_term, weight, doc_idx, snt_idx, wrd_idx);
-
-                //let text = docs_db.get::<String>(&doc_idx).unwrap();
-                //println!("{}", text);
+        }
+        // Print results
+        for (correct_term, weight, Posting { doc_idx, snt_idx, wrd_idx }) in docs {
+            let movie = match output.registry {
+                Some(registry) => registry.describe(doc_idx),
+                None => format!("document {}", doc_idx),
+            };
+            println!("Hit {} {} {}:{}:{} {}", correct_term, weight, doc_idx, snt_idx, wrd_idx,
+                     movie);
+            let translation = output.translations
+                .and_then(|translations| translations.translation(doc_idx, snt_idx));
+            if let Some(translation) = translation {
+                println!("Translation {}", translation);
             }
         }
-    });
+    }
 }
 
 /*
@@ -520,6 +887,12 @@ fn intersect2<'a, 'b, I1, I2>(pl1_it: I1, pl2_it: I2, compares: &mut u64) -> Vec
 }
 */
 
+fn map_size_arg(sub_m: &clap::ArgMatches) -> usize {
+    sub_m.value_of("mapsize")
+        .map(|megabytes| megabytes.parse::<usize>().unwrap() * 1_000_000)
+        .unwrap_or(DEFAULT_MAP_SIZE)
+}
+
 fn main() {
     let matches = clap_app!(movie_search =>
         (@setting SubcommandRequiredElseHelp)
@@ -529,77 +902,293 @@ fn main() {
         (@arg verbose: -v --verbose "Print information about the information verbosely")
         (@subcommand preindex =>
             (about: "Preindex a text")
-            (@arg COLLECTION: +required "The input file representing the document collection")
+            (@arg COLLECTION: +required
+                "The directory, or zip, tar or tar.gz archive, of subtitle files to index")
             (@arg PREINDEX: +required "The file to output the preindex to")
-            (@arg TDF: +required "The file to output the term document frequencies to")
-            (@arg lowercase: -l --lower "Lowercase the index"))
+            (@arg STATS: +required "The file to output term and document statistics to")
+            (@arg lowercase: -l --lower "Lowercase the index")
+            (@arg lemmas: --lemmas
+                "Also index the lemma of each word, where the subtitles are annotated with them, \
+                 at the same position as the word")
+            (@arg raw: --raw
+                "The collection is a raw release, with untokenized text in its sentences, which \
+                 is split into words by the built-in Finnish tokenizer")
+            (@arg filter: --filter +takes_value
+                "Only index the documents matching this corpus filter expression \
+                 (default source.original contains Finnish). Metadata tests don't apply to SRT \
+                 and WebVTT files, which have none")
+            (@arg strict: --strict
+                "Skip subtitle files with malformed timestamps, sentences or words rather than \
+                 recovering what can be recovered")
+            (@arg select: --select +takes_value
+                "How to choose among several subtitle files for a movie: a comma separated list \
+                 of original-finnish, rating, downloads and tokens, each breaking the ties of \
+                 the ones before (default original-finnish,rating,downloads,tokens)")
+            (@arg choose: --choose +takes_value
+                "A file listing subtitle files, relative to COLLECTION, to always choose")
+            (@arg mapsize: --mapsize +takes_value
+                "The initial LMDB map size in megabytes, doubled whenever it fills up")
+            (@arg force: -f --force "Replace existing outputs"))
         (@subcommand repl =>
             (about: ("Enter a REPL in which search terms can be entered and results will be \
                       returned."))
-            (@arg FSTINDEX: +required "The file to read the FST index from")
-            (@arg POSTINGS: +required "The file to read the postings list from")
+            (@arg INDEX: +required "The file to read the FST index or packed index from")
             (@arg ERROR_MODEL: +required "The file to read the error model from")
             (@arg DUMP_FILE: "The file to dump the query FSA to")
-            (@arg lowercase: -l --lower "Lowercase the query"))
+            (@arg postings: -p --postings +takes_value
+                "The file to read the postings list from, unless INDEX is packed")
+            (@arg termstats: -s --termstats +takes_value
+                "The file to read term statistics from to show with verbose output")
+            (@arg lowercase: -l --lower
+                "Lowercase the query. Only needed for indexes without a manifest, otherwise \
+                 queries are normalized like the index.")
+            (@arg hits: -n --hits +takes_value
+                "Show this many of the best matching occurrences along with the movies they are \
+                 from (default 0)")
+            (@arg translations: -t --translations +takes_value
+                "A translations database made by align, to show the translation of the sentence \
+                 of each hit from")
+            (@arg json: -j --json "Output one JSON object per query instead of text"))
+        (@subcommand align =>
+            (about: "Store the translations of the indexed sentences from an OPUS sentence \
+                     alignment, for the REPL to show with hits")
+            (@arg INDEX: +required "The FST index or packed index whose documents to translate")
+            (@arg ALIGNMENT: +required
+                "The OPUS sentence alignment file between the indexed subtitles and another \
+                 language, like en-fi.xml.gz")
+            (@arg COLLECTION: +required
+                "The directory, or zip, tar or tar.gz archive, of subtitle files in the other \
+                 language")
+            (@arg TRANSLATIONS: +required "The file to output the translations database to")
+            (@arg mapsize: --mapsize +takes_value
+                "The initial LMDB map size in megabytes, doubled whenever it fills up")
+            (@arg force: -f --force "Replace existing outputs"))
         (@subcommand stats =>
             (about: ("Read stats about the index and postings lists."))
-            (@arg FSTINDEX: +required "The file to output the FST index")
-            (@arg POSTINGS: +required "The file to output the postings list from the FST"))
+            (@arg INDEX: +required "The file to read the FST index or packed index from")
+            (@arg postings: -p --postings +takes_value
+                "The file to read the postings list from, unless INDEX is packed")
+            (@arg termstats: -s --termstats +takes_value
+                "The file to read term and document statistics from")
+            (@arg top: -n --top +takes_value "How many of the most frequent terms to list")
+            (@arg json: -j --json "Output JSON instead of text"))
+        (@subcommand lookup =>
+            (about: "Show what the index knows about a term")
+            (@arg INDEX: +required "The file to read the FST index or packed index from")
+            (@arg TERM: +required "The term to look up")
+            (@arg postings: -p --postings +takes_value
+                "The file to read the postings list from, unless INDEX is packed")
+            (@arg termstats: -s --termstats +takes_value
+                "The file to read term and document statistics from")
+            (@arg contexts: -c --contexts +takes_value
+                "A preindex to reconstruct the sentences containing the term from")
+            (@arg neighbours: -n --neighbours +takes_value
+                "List this many terms either side of the term in the index")
+            (@arg prefix: --prefix "List the terms starting with the term")
+            (@arg limit: --limit +takes_value
+                "The most postings, contexts or prefixed terms to list (default 50)")
+            (@arg lowercase: -l --lower
+                "Lowercase the term. Only needed for indexes without a manifest."))
+        (@subcommand fsck =>
+            (about: "Check an index for corruption and consistency with what it was built from")
+            (@arg INDEX: +required "The file to read the FST index or packed index from")
+            (@arg postings: -p --postings +takes_value
+                "The file to read the postings list from, unless INDEX is packed")
+            (@arg termstats: -s --termstats +takes_value
+                "The term and document statistics to check against the index")
+            (@arg preindex: -i --preindex +takes_value
+                "The preindex to check and check the index against"))
+        (@subcommand pack =>
+            (about: "Pack an FST index and its postings into a single memory mappable file")
+            (@arg FSTINDEX: +required "The FST index to read from")
+            (@arg POSTINGS: +required "The postings list to read from")
+            (@arg INDEX: +required "The file to output the packed index to")
+            (@arg force: -f --force "Replace existing outputs"))
         (@subcommand fstindex =>
             (about: "Produce an efficient FST index from a preindex")
             (@arg PREINDEX: +required "The preindex to read from")
             (@arg FSTINDEX: +required "The file to output the FST index")
             (@arg POSTINGS: +required "The file to output the postings list from the FST")
-            (@arg stopwords: "A file containing a list of stopwords"))
+            (@arg stopwords: "A file containing a list of stopwords")
+            (@arg max_df: -m --maxdf +takes_value
+                "Also stop terms occurring in more than this many documents, or this proportion \
+                 of documents if below 1")
+            (@arg stoplist_out: -s --savestop +takes_value
+                "The file to write the stopwords removed from the index to")
+            (@arg mapsize: --mapsize +takes_value
+                "The initial LMDB map size in megabytes, doubled whenever it fills up")
+            (@arg force: -f --force "Replace existing outputs"))
     ).get_matches();
 
     match matches.subcommand() {
         ("preindex", Some(sub_m)) => {
-            preindex(sub_m.value_of("COLLECTION").unwrap(),
+            let collection_dir = sub_m.value_of("COLLECTION").unwrap();
+            let mut policy = SelectionPolicy::parse(sub_m.value_of("select")
+                                                        .unwrap_or(DEFAULT_POLICY))
+                .unwrap_or_else(|err| fail(&err));
+            if let Some(chosen_fn) = sub_m.value_of("choose") {
+                policy.read_chosen(chosen_fn).unwrap_or_else(|err| fail(&err));
+            }
+            let corpus_filter = CorpusFilter::parse(sub_m.value_of("filter")
+                                                        .unwrap_or(CORPUS_FILTER))
+                .unwrap_or_else(|err| fail(&err));
+            preindex(collection_dir,
                      sub_m.value_of("PREINDEX").unwrap(),
-                     sub_m.value_of("TDF").unwrap(),
-                     sub_m.is_present("lowercase"));
+                     sub_m.value_of("STATS").unwrap(),
+                     sub_m.is_present("lowercase"),
+                     sub_m.is_present("lemmas"),
+                     if sub_m.is_present("raw") { Tokenization::Raw } else { Tokenization::Words },
+                     &corpus_filter,
+                     &policy,
+                     sub_m.is_present("strict"),
+                     map_size_arg(sub_m),
+                     sub_m.is_present("force"));
         }
         ("fstindex", Some(sub_m)) => {
             fstindex(sub_m.value_of("PREINDEX").unwrap(),
                      sub_m.value_of("FSTINDEX").unwrap(),
-                     sub_m.value_of("POSTINGS").unwrap());
+                     sub_m.value_of("POSTINGS").unwrap(),
+                     sub_m.value_of("stopwords"),
+                     sub_m.value_of("max_df").map(|max_df| max_df.parse::<f64>().unwrap()),
+                     sub_m.value_of("stoplist_out"),
+                     map_size_arg(sub_m),
+                     sub_m.is_present("force"));
         }
         ("repl", Some(sub_m)) => {
             let error_model = sub_m.value_of("ERROR_MODEL").unwrap();
-            if error_model.starts_with("levenshtein-") {
-                let mut bits = error_model.splitn(2, "-");
-                bits.next().unwrap();
-                let num = bits.next().unwrap();
-                let num = num.parse::<f64>().unwrap();
-                repl(sub_m.value_of("FSTINDEX").unwrap(),
-                     sub_m.value_of("POSTINGS").unwrap(),
-                     sub_m.is_present("lowercase"),
-                     sub_m.value_of("DUMP_FILE"),
-                     matches.is_present("verbose"),
-                     |query| {
-                        mk_levenshtein(query, num, 256)
-                     },
-                     get_levenshtein_weights);
-            } else {
-                let err_model = TransducerBox::from_file(error_model)
-                    .expect("Error model not found");
-                repl(sub_m.value_of("FSTINDEX").unwrap(),
-                     sub_m.value_of("POSTINGS").unwrap(),
-                     sub_m.is_present("lowercase"),
-                     sub_m.value_of("DUMP_FILE"),
-                     matches.is_present("verbose"),
-                     |query| {
-                         mk_stack(
-                             err_model.text_to_denoised_fsa(query, false, false).unwrap(),
-                             30.0, 256)
-                     },
-                     get_weights);
-            }
+            with_index(sub_m.value_of("INDEX").unwrap(),
+                       sub_m.value_of("postings"),
+                       |map, postings_source, manifest, registry| {
+                let lowercase = query_lowercase(manifest, sub_m.is_present("lowercase"));
+                with_translations(sub_m.value_of("translations"), |translations| {
+                    let output = ReplOutput {
+                        registry: registry,
+                        translations: translations,
+                        num_hits: sub_m.value_of("hits")
+                            .map(|hits| hits.parse::<usize>().unwrap())
+                            .unwrap_or(0),
+                        json: sub_m.is_present("json"),
+                    };
+                    with_stats(sub_m.value_of("termstats"), |term_stats| {
+                        if error_model.starts_with("levenshtein-") {
+                            let mut bits = error_model.splitn(2, "-");
+                            bits.next().unwrap();
+                            let num = bits.next().unwrap();
+                            let num = num.parse::<f64>().unwrap();
+                            repl(map,
+                                 postings_source,
+                                 term_stats,
+                                 lowercase,
+                                 sub_m.value_of("DUMP_FILE"),
+                                 matches.is_present("verbose"),
+                                 &output,
+                                 |query| {
+                                    mk_levenshtein(query, num, 256)
+                                 },
+                                 get_levenshtein_weights);
+                        } else {
+                            let err_model = TransducerBox::from_file(error_model)
+                                .expect("Error model not found");
+                            repl(map,
+                                 postings_source,
+                                 term_stats,
+                                 lowercase,
+                                 sub_m.value_of("DUMP_FILE"),
+                                 matches.is_present("verbose"),
+                                 &output,
+                                 |query| {
+                                     let fsa = err_model
+                                         .text_to_denoised_fsa(query, false, false)
+                                         .unwrap();
+                                     mk_stack(fsa, 30.0, 256)
+                                 },
+                                 get_weights);
+                        }
+                    });
+                });
+            });
         }
         ("stats", Some(sub_m)) => {
-            stats(sub_m.value_of("FSTINDEX").unwrap(),
-                  sub_m.value_of("POSTINGS").unwrap());
+            let stats_fn = sub_m.value_of("termstats");
+            let top_n = sub_m.value_of("top").map(|top_n| top_n.parse::<usize>().unwrap())
+                .unwrap_or(20);
+            with_index(sub_m.value_of("INDEX").unwrap(),
+                       sub_m.value_of("postings"),
+                       |map, postings_source, manifest, registry| {
+                with_stats(stats_fn, |term_stats| {
+                    let index_stats = IndexStats::new(map, postings_source, manifest, registry,
+                                                      term_stats);
+                    if sub_m.is_present("json") {
+                        index_stats.print_json(top_n);
+                    } else {
+                        index_stats.print_text(top_n);
+                    }
+                })
+            });
+        }
+        ("lookup", Some(sub_m)) => {
+            let options = LookupOptions {
+                contexts_fn: sub_m.value_of("contexts"),
+                neighbours: sub_m.value_of("neighbours")
+                    .map(|neighbours| neighbours.parse::<usize>().unwrap())
+                    .unwrap_or(0),
+                prefix: sub_m.is_present("prefix"),
+                limit: sub_m.value_of("limit").map(|limit| limit.parse::<usize>().unwrap())
+                    .unwrap_or(50),
+            };
+            with_index(sub_m.value_of("INDEX").unwrap(),
+                       sub_m.value_of("postings"),
+                       |map, postings_source, manifest, registry| {
+                let term = sub_m.value_of("TERM").unwrap();
+                let term = if query_lowercase(manifest, sub_m.is_present("lowercase")) {
+                    term.to_lowercase()
+                } else {
+                    term.to_owned()
+                };
+                with_stats(sub_m.value_of("termstats"), |term_stats| {
+                    lookup(map, postings_source, term_stats, registry, &term, &options)
+                })
+            });
+        }
+        ("fsck", Some(sub_m)) => {
+            let mut num_problems = 0;
+            with_index(sub_m.value_of("INDEX").unwrap(),
+                       sub_m.value_of("postings"),
+                       |map, postings_source, manifest, registry| {
+                with_stats(sub_m.value_of("termstats"), |term_stats| {
+                    num_problems = fsck::fsck(map, postings_source, manifest, registry,
+                                              term_stats, sub_m.value_of("preindex"));
+                })
+            });
+            if num_problems > 0 {
+                fail(&format!("{} problems found", num_problems));
+            }
+            println!("No problems found");
+        }
+        ("align", Some(sub_m)) => {
+            let index_fn = sub_m.value_of("INDEX").unwrap();
+            let registry = if is_packed(index_fn) {
+                PackedIndex::open(index_fn).unwrap().registry
+            } else {
+                Registry::load(index_fn)
+                    .unwrap_or_else(|err| fail(&format!("{}: {}", index_fn, err)))
+            };
+            let registry = registry.unwrap_or_else(|| {
+                fail(&format!("{} has no document registry to match the alignment against",
+                              index_fn))
+            });
+            align(&registry,
+                  sub_m.value_of("ALIGNMENT").unwrap(),
+                  sub_m.value_of("COLLECTION").unwrap(),
+                  sub_m.value_of("TRANSLATIONS").unwrap(),
+                  map_size_arg(sub_m),
+                  sub_m.is_present("force"));
+        }
+        ("pack", Some(sub_m)) => {
+            pack_index(sub_m.value_of("FSTINDEX").unwrap(),
+                       sub_m.value_of("POSTINGS").unwrap(),
+                       sub_m.value_of("INDEX").unwrap(),
+                       sub_m.is_present("force"));
         }
         (_, _) => {
             panic!("Shan't")
@@ -0,0 +1,153 @@
+//! Index manifests.
+//!
+//! Every build step writes a manifest next to its output, at the output path with `.manifest`
+//! appended, recording how the index was built. Packed indexes carry the manifest in their
+//! metadata section instead. Readers use it to normalize queries the same way as the index and
+//! to refuse indexes they can't read correctly.
+//!
+//! Manifests are text files with one `key = value` pair per line. Keys besides the fixed fields
+//! below are free-form build options of the individual steps.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use invalid_data;
+
+/// Bumped whenever an index built by an older version can no longer be read correctly.
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    pub schema_version: u64,
+    pub lowercase: bool,
+    pub tokenizer: String,
+    pub corpus_filter: String,
+    pub built: String,
+    pub num_docs: u64,
+    pub options: BTreeMap<String, String>,
+}
+
+pub fn manifest_path(output_fn: &str) -> String {
+    format!("{}.manifest", output_fn)
+}
+
+/// The current time in UTC formatted like 2017-05-01T12:00:00Z.
+pub fn utc_now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch to a civil date, after Howard Hinnant's civil_from_days. Only
+    // dates after the epoch are needed so everything stays unsigned.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+            secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+impl Manifest {
+    pub fn new(lowercase: bool, tokenizer: &str, corpus_filter: &str, num_docs: u64) -> Manifest {
+        Manifest {
+            schema_version: SCHEMA_VERSION,
+            lowercase: lowercase,
+            tokenizer: tokenizer.to_owned(),
+            corpus_filter: corpus_filter.to_owned(),
+            built: utc_now(),
+            num_docs: num_docs,
+            options: BTreeMap::new(),
+        }
+    }
+
+    pub fn to_map(&self) -> BTreeMap<String, String> {
+        let mut map = self.options.clone();
+        map.insert("schema_version".to_owned(), self.schema_version.to_string());
+        map.insert("lowercase".to_owned(), self.lowercase.to_string());
+        map.insert("tokenizer".to_owned(), self.tokenizer.clone());
+        map.insert("corpus_filter".to_owned(), self.corpus_filter.clone());
+        map.insert("built".to_owned(), self.built.clone());
+        map.insert("num_docs".to_owned(), self.num_docs.to_string());
+        map
+    }
+
+    pub fn from_map(mut map: BTreeMap<String, String>) -> io::Result<Manifest> {
+        fn take(map: &mut BTreeMap<String, String>, key: &str) -> io::Result<String> {
+            map.remove(key)
+                .ok_or_else(|| invalid_data(format!("manifest is missing '{}'", key)))
+        }
+        fn parse<T: ::std::str::FromStr>(key: &str, value: String) -> io::Result<T> {
+            value.parse::<T>()
+                .map_err(|_| invalid_data(format!("bad manifest value for '{}': {}", key, value)))
+        }
+        let schema_version = take(&mut map, "schema_version")?;
+        let schema_version = parse("schema_version", schema_version)?;
+        if schema_version != SCHEMA_VERSION {
+            return Err(invalid_data(format!(
+                "index has schema version {} but this build reads version {}; rebuild the index",
+                schema_version, SCHEMA_VERSION)));
+        }
+        let lowercase = take(&mut map, "lowercase")?;
+        let num_docs = take(&mut map, "num_docs")?;
+        Ok(Manifest {
+            schema_version: schema_version,
+            lowercase: parse("lowercase", lowercase)?,
+            tokenizer: take(&mut map, "tokenizer")?,
+            corpus_filter: take(&mut map, "corpus_filter")?,
+            built: take(&mut map, "built")?,
+            num_docs: parse("num_docs", num_docs)?,
+            options: map,
+        })
+    }
+
+    /// Reads the manifest written next to output_fn, or None if there isn't one.
+    pub fn load(output_fn: &str) -> io::Result<Option<Manifest>> {
+        let path = manifest_path(output_fn);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let mut map = BTreeMap::new();
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut bits = line.splitn(2, " = ");
+            match (bits.next(), bits.next()) {
+                (Some(key), Some(value)) => {
+                    map.insert(key.to_owned(), value.to_owned());
+                }
+                _ => return Err(invalid_data(format!("bad manifest line '{}' in {}", line, path))),
+            }
+        }
+        Manifest::from_map(map).map(Some)
+    }
+
+    /// Writes the manifest next to output_fn.
+    pub fn save(&self, output_fn: &str) -> io::Result<()> {
+        let mut outf = File::create(manifest_path(output_fn))?;
+        for (key, value) in self.to_map() {
+            writeln!(outf, "{} = {}", key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Decides whether queries should be lowercased given whether the user asked for it. Queries
+    /// against a lowercased index are always lowercased, while lowercasing queries against an
+    /// index which kept case would miss every capitalized term.
+    pub fn query_lowercase(&self, requested: bool) -> Result<bool, String> {
+        if requested && !self.lowercase {
+            Err("the index was built without lowercasing, so lowercased queries would miss \
+                 capitalized terms".to_owned())
+        } else {
+            Ok(self.lowercase)
+        }
+    }
+}
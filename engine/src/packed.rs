@@ -0,0 +1,238 @@
+//! Single file index format.
+//!
+//! A packed index holds everything `repl` and `stats` need in one read-only, memory-mapped file,
+//! so it can be copied around as a unit and isn't limited by an LMDB map size. It is made from an
+//! FST index and postings database with the `pack` subcommand.
+//!
+//! Layout, with all integers big-endian:
+//!
+//! ```text
+//! header   := MAGIC u64(PACKED_VERSION) u64(num_docs) u64(num_terms) u64(num_postings)
+//!             section(postings) section(fst) section(meta) section(docs)
+//! section  := u64(offset) u64(len)
+//! postings := (u64(len) postings_value)*
+//! fst      := an FST mapping each term to the offset of its entry in postings
+//! meta     := (key '\t' value '\n')*
+//! docs     := a document registry as described in the `registry` module, or nothing
+//! ```
+//!
+//! Offsets are from the start of the file. Each postings value is encoded as described in the
+//! `postings` module.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+use std::str;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use fst::{Map, MapBuilder, Streamer};
+use fst::raw::{Fst, MmapReadOnly};
+use postings::{PostingsList, PostingsError, PostingsSource, encode_postings, decode_postings_list};
+use registry::Registry;
+use invalid_data;
+
+pub const MAGIC: &'static [u8; 8] = b"MVSRCHPK";
+pub const PACKED_VERSION: u64 = 2;
+const HEADER_LEN: usize = 8 + 12 * 8;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Section {
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Header {
+    pub num_docs: u64,
+    pub num_terms: u64,
+    pub num_postings: u64,
+    postings: Section,
+    fst: Section,
+    meta: Section,
+    docs: Section,
+}
+
+impl Header {
+    fn write<W: Write>(&self, wtr: &mut W) -> io::Result<()> {
+        wtr.write_all(MAGIC)?;
+        for &field in &[PACKED_VERSION, self.num_docs, self.num_terms, self.num_postings,
+                        self.postings.offset, self.postings.len,
+                        self.fst.offset, self.fst.len,
+                        self.meta.offset, self.meta.len,
+                        self.docs.offset, self.docs.len] {
+            wtr.write_u64::<BigEndian>(field)?;
+        }
+        Ok(())
+    }
+
+    fn read(buf: &[u8]) -> io::Result<Header> {
+        if buf.len() < HEADER_LEN || &buf[..8] != MAGIC {
+            return Err(invalid_data("not a packed index"));
+        }
+        let field = |idx: usize| BigEndian::read_u64(&buf[8 + idx * 8..]);
+        if field(0) != PACKED_VERSION {
+            return Err(invalid_data(format!(
+                "packed index version {} but this build reads version {}",
+                field(0), PACKED_VERSION)));
+        }
+        Ok(Header {
+            num_docs: field(1),
+            num_terms: field(2),
+            num_postings: field(3),
+            postings: Section { offset: field(4), len: field(5) },
+            fst: Section { offset: field(6), len: field(7) },
+            meta: Section { offset: field(8), len: field(9) },
+            docs: Section { offset: field(10), len: field(11) },
+        })
+    }
+}
+
+fn encode_meta(meta: &BTreeMap<String, String>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (key, value) in meta {
+        buf.extend_from_slice(key.as_bytes());
+        buf.push(b'\t');
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+fn decode_meta(buf: &[u8]) -> io::Result<BTreeMap<String, String>> {
+    let text = str::from_utf8(buf).map_err(invalid_data)?;
+    text.lines()
+        .map(|line| {
+            let mut bits = line.splitn(2, '\t');
+            match (bits.next(), bits.next()) {
+                (Some(key), Some(value)) => Ok((key.to_owned(), value.to_owned())),
+                _ => Err(invalid_data(format!("bad metadata line '{}'", line))),
+            }
+        })
+        .collect()
+}
+
+/// Returns true if the file at path starts with the packed index magic number.
+pub fn is_packed<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0; 8];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map(|_| &magic == MAGIC)
+        .unwrap_or(false)
+}
+
+/// Writes a packed index containing every term in map along with its postings from source.
+/// meta should only contain keys and values without tabs or newlines.
+pub fn pack(map: &Map, source: &PostingsSource, meta: &BTreeMap<String, String>,
+            registry: Option<&Registry>, index_fn: &str) -> io::Result<()> {
+    let mut outf = BufWriter::new(File::create(index_fn)?);
+    // Filled in at the end once the section offsets are known
+    outf.write_all(&[0; HEADER_LEN])?;
+    let mut header = Header::default();
+    let mut docs: HashSet<u64> = HashSet::new();
+    let mut map_builder = MapBuilder::memory();
+    let mut postings_len = 0;
+    let mut stream = map.stream();
+    while let Some((term, value)) = stream.next() {
+        let postings = source.postings(value).map_err(invalid_data)?;
+        docs.extend(postings.iter().map(|posting| posting.doc_idx));
+        header.num_terms += 1;
+        header.num_postings += postings.len() as u64;
+        let encoded = encode_postings(&postings);
+        map_builder.insert(term, postings_len).map_err(invalid_data)?;
+        outf.write_u64::<BigEndian>(encoded.len() as u64)?;
+        outf.write_all(&encoded)?;
+        postings_len += 8 + encoded.len() as u64;
+    }
+    header.num_docs = docs.len() as u64;
+    header.postings = Section { offset: HEADER_LEN as u64, len: postings_len };
+
+    let fst_bytes = map_builder.into_inner().map_err(invalid_data)?;
+    header.fst = Section {
+        offset: header.postings.offset + header.postings.len,
+        len: fst_bytes.len() as u64
+    };
+    outf.write_all(&fst_bytes)?;
+
+    let meta_bytes = encode_meta(meta);
+    header.meta = Section {
+        offset: header.fst.offset + header.fst.len,
+        len: meta_bytes.len() as u64
+    };
+    outf.write_all(&meta_bytes)?;
+
+    let docs_bytes = registry.map(|registry| registry.encode()).unwrap_or_else(Vec::new);
+    header.docs = Section {
+        offset: header.meta.offset + header.meta.len,
+        len: docs_bytes.len() as u64
+    };
+    outf.write_all(&docs_bytes)?;
+
+    let mut outf = outf.into_inner()?;
+    outf.seek(SeekFrom::Start(0))?;
+    header.write(&mut outf)
+}
+
+pub struct PackedIndex {
+    pub header: Header,
+    pub meta: BTreeMap<String, String>,
+    pub registry: Option<Registry>,
+    map: Map,
+    postings: MmapReadOnly,
+}
+
+impl PackedIndex {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PackedIndex> {
+        let mmap = MmapReadOnly::open_path(path)?;
+        // Sound as long as nobody modifies the file while it is mapped. Indexes are only ever
+        // written whole by pack.
+        let header = Header::read(unsafe { mmap.as_slice() })?;
+        let section = |section: &Section| {
+            let in_bounds = section.offset.checked_add(section.len)
+                .map(|end| end <= mmap.len() as u64)
+                .unwrap_or(false);
+            if in_bounds {
+                Ok(mmap.range(section.offset as usize, section.len as usize))
+            } else {
+                Err(invalid_data("packed index section out of bounds"))
+            }
+        };
+        let fst = Fst::from_mmap(section(&header.fst)?).map_err(invalid_data)?;
+        let meta_mmap = section(&header.meta)?;
+        let meta = decode_meta(unsafe { meta_mmap.as_slice() })?;
+        let registry = if header.docs.len > 0 {
+            let docs_mmap = section(&header.docs)?;
+            Some(Registry::decode(unsafe { docs_mmap.as_slice() })?)
+        } else {
+            None
+        };
+        Ok(PackedIndex {
+            header: header,
+            meta: meta,
+            registry: registry,
+            map: Map::from(fst),
+            postings: section(&header.postings)?,
+        })
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+}
+
+impl PostingsSource for PackedIndex {
+    fn postings(&self, value: u64) -> Result<PostingsList, PostingsError> {
+        let section = unsafe { self.postings.as_slice() };
+        let start = value as usize;
+        if start > section.len() || section.len() - start < 8 {
+            return Err(PostingsError::Missing(value));
+        }
+        let len = BigEndian::read_u64(&section[start..]) as usize;
+        let section = &section[start + 8..];
+        if len > section.len() {
+            return Err(PostingsError::Truncated);
+        }
+        decode_postings_list(&section[..len])
+    }
+}
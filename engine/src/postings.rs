@@ -0,0 +1,412 @@
+//! Block-compressed postings lists.
+//!
+//! A postings list is sorted by document, then sentence, then word. Each posting is written as
+//! the difference from the posting before it using variable-byte integers: sentence and word
+//! indices are only delta coded while the document (and sentence) stay the same, and are written
+//! in full otherwise. Postings are grouped into blocks of at most `BLOCK_SIZE`, each starting
+//! from scratch and prefixed with its last document index and encoded length so a reader can
+//! step over whole blocks without decoding them.
+//!
+//! The encoding is independent of host endianness and struct layout. Every value starts with a
+//! format version byte, and decoding checks every read against the end of the buffer and the
+//! declared block lengths, so truncated or corrupt values give a `PostingsError` rather than
+//! garbage postings.
+//!
+//! Layout:
+//!
+//! ```text
+//! list   := u8(FORMAT_VERSION) varint(num_postings) block*
+//! block  := varint(num_in_block) varint(last_doc_idx) varint(body_len) body
+//! varint := little-endian base 128, high bit set on all but the last byte
+//! ```
+//!
+//! Keys in the postings database are the term's FST value as a big-endian u64, see `term_key`.
+
+use std::error::Error;
+use std::fmt;
+use byteorder::{BigEndian, ByteOrder};
+
+pub const BLOCK_SIZE: usize = 128;
+pub const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PostingsError {
+    Missing(u64),
+    Empty,
+    VersionMismatch(u8),
+    Truncated,
+    VarintOverflow,
+    IndexOverflow,
+    BlockLength { expected: usize, actual: usize },
+    BlockCount { remaining: usize, declared: u64 },
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for PostingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PostingsError::Missing(value) =>
+                write!(f, "no postings stored for FST value {}", value),
+            PostingsError::VersionMismatch(version) =>
+                write!(f, "postings format version {} but this build reads version {}",
+                       version, FORMAT_VERSION),
+            PostingsError::BlockLength { expected, actual } =>
+                write!(f, "postings block declared {} bytes but decoded {}", expected, actual),
+            PostingsError::BlockCount { remaining, declared } =>
+                write!(f, "postings block declared {} postings with {} left in the list",
+                       declared, remaining),
+            PostingsError::TrailingBytes(num) =>
+                write!(f, "{} unexpected bytes after postings list", num),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for PostingsError {
+    fn description(&self) -> &str {
+        match *self {
+            PostingsError::Missing(_) => "no postings stored for term",
+            PostingsError::Empty => "empty postings value",
+            PostingsError::VersionMismatch(_) => "unsupported postings format version",
+            PostingsError::Truncated => "postings value ends unexpectedly",
+            PostingsError::VarintOverflow => "varint in postings value exceeds 64 bits",
+            PostingsError::IndexOverflow => "delta in postings value overflows index",
+            PostingsError::BlockLength { .. } => "postings block length mismatch",
+            PostingsError::BlockCount { .. } => "bad postings block count",
+            PostingsError::TrailingBytes(_) => "trailing bytes after postings list",
+        }
+    }
+}
+
+/// Somewhere postings lists can be looked up by the FST value of their term.
+pub trait PostingsSource {
+    fn postings(&self, value: u64) -> Result<PostingsList, PostingsError>;
+}
+
+/// Returns the postings database key for the term with FST value idx.
+pub fn term_key(idx: u64) -> [u8; 8] {
+    let mut key = [0; 8];
+    BigEndian::write_u64(&mut key, idx);
+    key
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Posting {
+    pub doc_idx: u64,
+    pub snt_idx: u64,
+    pub wrd_idx: u64,
+}
+
+const NO_POSTING: Posting = Posting { doc_idx: 0, snt_idx: 0, wrd_idx: 0 };
+
+pub type PostingsList = Vec<Posting>;
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64, PostingsError> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *buf.get(*pos).ok_or(PostingsError::Truncated)?;
+        *pos += 1;
+        let bits = (byte & 0x7f) as u64;
+        if shift >= 64 || (shift > 0 && bits >> (64 - shift) != 0) {
+            return Err(PostingsError::VarintOverflow);
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn add_delta(base: u64, delta: u64) -> Result<u64, PostingsError> {
+    base.checked_add(delta).ok_or(PostingsError::IndexOverflow)
+}
+
+fn write_posting(buf: &mut Vec<u8>, prev: &Posting, posting: &Posting) {
+    let doc_delta = posting.doc_idx - prev.doc_idx;
+    write_varint(buf, doc_delta);
+    if doc_delta != 0 {
+        write_varint(buf, posting.snt_idx);
+        write_varint(buf, posting.wrd_idx);
+        return;
+    }
+    let snt_delta = posting.snt_idx - prev.snt_idx;
+    write_varint(buf, snt_delta);
+    if snt_delta != 0 {
+        write_varint(buf, posting.wrd_idx);
+    } else {
+        write_varint(buf, posting.wrd_idx - prev.wrd_idx);
+    }
+}
+
+fn read_posting(buf: &[u8], pos: &mut usize, prev: &Posting) -> Result<Posting, PostingsError> {
+    let doc_delta = read_varint(buf, pos)?;
+    if doc_delta != 0 {
+        return Ok(Posting {
+            doc_idx: add_delta(prev.doc_idx, doc_delta)?,
+            snt_idx: read_varint(buf, pos)?,
+            wrd_idx: read_varint(buf, pos)?,
+        });
+    }
+    let snt_delta = read_varint(buf, pos)?;
+    if snt_delta != 0 {
+        Ok(Posting {
+            doc_idx: prev.doc_idx,
+            snt_idx: add_delta(prev.snt_idx, snt_delta)?,
+            wrd_idx: read_varint(buf, pos)?,
+        })
+    } else {
+        Ok(Posting {
+            doc_idx: prev.doc_idx,
+            snt_idx: prev.snt_idx,
+            wrd_idx: add_delta(prev.wrd_idx, read_varint(buf, pos)?)?,
+        })
+    }
+}
+
+/// Encodes a postings list, which must already be sorted.
+pub fn encode_postings(postings: &[Posting]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(postings.len() * 4 + 1);
+    buf.push(FORMAT_VERSION);
+    write_varint(&mut buf, postings.len() as u64);
+    let mut body = Vec::new();
+    for block in postings.chunks(BLOCK_SIZE) {
+        body.clear();
+        let mut prev = NO_POSTING;
+        for posting in block {
+            write_posting(&mut body, &prev, posting);
+            prev = *posting;
+        }
+        write_varint(&mut buf, block.len() as u64);
+        write_varint(&mut buf, prev.doc_idx);
+        write_varint(&mut buf, body.len() as u64);
+        buf.extend_from_slice(&body);
+    }
+    buf
+}
+
+/// Checks the format version of the postings list in buf and returns an iterator decoding it.
+/// The iterator stops after yielding the first error.
+pub fn decode_postings(buf: &[u8]) -> Result<PostingsIter, PostingsError> {
+    let version = *buf.first().ok_or(PostingsError::Empty)?;
+    if version != FORMAT_VERSION {
+        return Err(PostingsError::VersionMismatch(version));
+    }
+    let mut pos = 1;
+    let remaining = read_varint(buf, &mut pos)? as usize;
+    if remaining == 0 && pos != buf.len() {
+        return Err(PostingsError::TrailingBytes(buf.len() - pos));
+    }
+    Ok(PostingsIter {
+        buf: buf,
+        pos: pos,
+        remaining: remaining,
+        block_remaining: 0,
+        block_start: pos,
+        block_end: pos,
+        prev: NO_POSTING,
+        failed: false,
+    })
+}
+
+/// Decodes the whole postings list in buf.
+pub fn decode_postings_list(buf: &[u8]) -> Result<PostingsList, PostingsError> {
+    decode_postings(buf).and_then(|postings| postings.collect())
+}
+
+pub struct PostingsIter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    remaining: usize,
+    block_remaining: usize,
+    block_start: usize,
+    block_end: usize,
+    prev: Posting,
+    failed: bool,
+}
+
+impl<'a> PostingsIter<'a> {
+    /// The number of postings not yet decoded, as declared by the list header.
+    pub fn num_postings(&self) -> usize {
+        self.remaining
+    }
+
+    fn start_block(&mut self) -> Result<(), PostingsError> {
+        let num_in_block = read_varint(self.buf, &mut self.pos)?;
+        // Blocks are never empty, and can't hold more than the postings left in the list
+        if num_in_block == 0 || num_in_block > self.remaining as u64 {
+            return Err(PostingsError::BlockCount {
+                remaining: self.remaining,
+                declared: num_in_block,
+            });
+        }
+        self.block_remaining = num_in_block as usize;
+        // The last document index is only needed to skip the block
+        read_varint(self.buf, &mut self.pos)?;
+        let body_len = read_varint(self.buf, &mut self.pos)? as usize;
+        if body_len > self.buf.len() - self.pos {
+            return Err(PostingsError::Truncated);
+        }
+        self.block_start = self.pos;
+        self.block_end = self.pos + body_len;
+        self.prev = NO_POSTING;
+        Ok(())
+    }
+
+    fn end_block(&self) -> Result<(), PostingsError> {
+        if self.pos != self.block_end || self.block_remaining != 0 {
+            return Err(PostingsError::BlockLength {
+                expected: self.block_end - self.block_start,
+                actual: self.pos - self.block_start,
+            });
+        }
+        if self.remaining == 0 && self.pos != self.buf.len() {
+            return Err(PostingsError::TrailingBytes(self.buf.len() - self.pos));
+        }
+        Ok(())
+    }
+
+    fn next_posting(&mut self) -> Result<Posting, PostingsError> {
+        if self.block_remaining == 0 {
+            self.start_block()?;
+        }
+        // Reading from the block's slice stops a posting from running over into the next block
+        let posting = read_posting(&self.buf[..self.block_end], &mut self.pos, &self.prev)?;
+        self.prev = posting;
+        self.remaining -= 1;
+        self.block_remaining -= 1;
+        if self.block_remaining == 0 || self.remaining == 0 {
+            self.end_block()?;
+        }
+        Ok(posting)
+    }
+}
+
+impl<'a> Iterator for PostingsIter<'a> {
+    type Item = Result<Posting, PostingsError>;
+
+    fn next(&mut self) -> Option<Result<Posting, PostingsError>> {
+        if self.remaining == 0 || self.failed {
+            return None;
+        }
+        let result = self.next_posting();
+        self.failed = result.is_err();
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.failed {
+            (0, Some(0))
+        } else {
+            (0, Some(self.remaining))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn postings(num: usize) -> PostingsList {
+        // Several postings per sentence and sentences per document, so every kind of delta
+        // comes up
+        (0..num as u64)
+            .map(|idx| Posting { doc_idx: idx / 20 * 3, snt_idx: idx / 4 % 5, wrd_idx: idx % 4 })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for &num in &[0, 1, BLOCK_SIZE, BLOCK_SIZE * 3 + 7] {
+            let list = postings(num);
+            assert_eq!(decode_postings_list(&encode_postings(&list)), Ok(list));
+        }
+    }
+
+    #[test]
+    fn round_trip_large_indices() {
+        let list = vec![
+            Posting { doc_idx: 0, snt_idx: u64::max_value(), wrd_idx: 0 },
+            Posting { doc_idx: u64::max_value(), snt_idx: 1, wrd_idx: u64::max_value() },
+        ];
+        assert_eq!(decode_postings_list(&encode_postings(&list)), Ok(list));
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = encode_postings(&postings(BLOCK_SIZE * 2 + 3));
+        for len in 1..buf.len() {
+            assert!(decode_postings_list(&buf[..len]).is_err(), "{} bytes decoded", len);
+        }
+        assert_eq!(decode_postings_list(&buf[..buf.len() - 1]), Err(PostingsError::Truncated));
+        assert_eq!(decode_postings_list(&buf[..1]), Err(PostingsError::Truncated));
+        assert_eq!(decode_postings_list(&[]), Err(PostingsError::Empty));
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut buf = encode_postings(&postings(3));
+        buf[0] = FORMAT_VERSION + 1;
+        assert_eq!(decode_postings_list(&buf),
+                   Err(PostingsError::VersionMismatch(FORMAT_VERSION + 1)));
+    }
+
+    #[test]
+    fn varint_overflow() {
+        let mut buf = vec![FORMAT_VERSION];
+        buf.extend_from_slice(&[0xff; 9]);
+        buf.push(0x02);
+        assert_eq!(decode_postings_list(&buf), Err(PostingsError::VarintOverflow));
+        // The largest value which fits still decodes
+        let mut buf = vec![FORMAT_VERSION];
+        buf.extend_from_slice(&[0xff; 9]);
+        buf.push(0x01);
+        assert!(decode_postings(&buf).is_ok());
+    }
+
+    #[test]
+    fn index_overflow() {
+        let list = vec![Posting { doc_idx: 0, snt_idx: 0, wrd_idx: u64::max_value() }];
+        let mut buf = encode_postings(&list);
+        // Append a posting in the same sentence one word on
+        buf[1] = 2;
+        buf[2] = 2;
+        let body_len = buf[4];
+        buf[4] = body_len + 3;
+        buf.extend_from_slice(&[0, 0, 1]);
+        assert_eq!(decode_postings_list(&buf), Err(PostingsError::IndexOverflow));
+    }
+
+    #[test]
+    fn bad_block_counts() {
+        let mut buf = encode_postings(&postings(3));
+        buf[2] = 0;
+        assert_eq!(decode_postings_list(&buf),
+                   Err(PostingsError::BlockCount { remaining: 3, declared: 0 }));
+        buf[2] = 4;
+        assert_eq!(decode_postings_list(&buf),
+                   Err(PostingsError::BlockCount { remaining: 3, declared: 4 }));
+    }
+
+    #[test]
+    fn block_length_and_trailing_bytes() {
+        let mut buf = encode_postings(&postings(3));
+        buf.push(0);
+        assert_eq!(decode_postings_list(&buf), Err(PostingsError::TrailingBytes(1)));
+        let mut buf = encode_postings(&postings(3));
+        // Declare one byte more for the block than its postings take up
+        buf[4] += 1;
+        buf.push(0);
+        assert_eq!(decode_postings_list(&buf),
+                   Err(PostingsError::BlockLength { expected: 10, actual: 9 }));
+    }
+}
@@ -0,0 +1,415 @@
+//! The document registry.
+//!
+//! Records where each indexed document came from and what movie it is. Subtitle files laid out like
+//! `<year>/<imdb id>/<subtitle id>.xml.gz` keep their IMDb id as their document id, so ids stay
+//! the same from build to build and existing test sets keep working. Any other file gets an id
+//! from `FIRST_UNNUMBERED_ID` up, in order of path.
+//!
+//! Like manifests, registries are written next to the output of each build step, at the output
+//! path with `.docs` appended, and packed indexes carry theirs in a section of their own. They
+//! are text files with one document per line:
+//!
+//! ```text
+//! doc_idx '\t' path '\t' imdb_id '\t' year '\t' subtitle_id '\t' title '\t' duration '\t'
+//!     genre '\n'
+//! ```
+//!
+//! where path is relative to the collection directory, with backslashes, tabs and line breaks in
+//! it escaped as `\\`, `\t`, `\n` and `\r`, and missing fields are written as `-`.
+//! Title, duration and genre come from the `<meta>` block of the subtitle file when it has them,
+//! as does the year when the path doesn't give it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::str;
+use opensubtitles::Meta;
+use option_filter::OptionFilterExt;
+use json::{json_str, json_opt_str, json_opt_u64};
+use invalid_data;
+
+/// Larger than any IMDb id.
+pub const FIRST_UNNUMBERED_ID: u64 = 1 << 32;
+
+#[derive(Clone, Debug)]
+pub struct Doc {
+    pub doc_idx: u64,
+    pub path: String,
+    pub imdb_id: Option<u64>,
+    pub year: Option<u64>,
+    pub subtitle_id: Option<u64>,
+    pub title: Option<String>,
+    pub duration: Option<String>,
+    pub genre: Option<String>,
+}
+
+/// Which movie a subtitle file belongs to, for choosing one file per movie.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MovieKey {
+    Imdb(u64),
+    /// Files outside the usual layout are each taken to be a movie of their own
+    Path(PathBuf),
+}
+
+impl fmt::Display for MovieKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieKey::Imdb(imdb_id) => write!(f, "{}", imdb_id),
+            MovieKey::Path(ref path) => write!(f, "{}", path.to_string_lossy()),
+        }
+    }
+}
+
+pub struct Registry {
+    docs: BTreeMap<u64, Doc>,
+}
+
+pub fn registry_path(output_fn: &str) -> String {
+    format!("{}.docs", output_fn)
+}
+
+fn numeric_component(component: Option<Component>) -> Option<u64> {
+    match component {
+        Some(Component::Normal(name)) => name.to_str().and_then(|name| name.parse::<u64>().ok()),
+        _ => None,
+    }
+}
+
+/// Reads the ids in a path like `<year>/<imdb id>/<subtitle id>.xml.gz`. Other paths, even
+/// ones with numeric directories like `season1/01/a.srt`, have none of the ids, since a number
+/// there can't be trusted to be an IMDb id.
+pub fn ids_of_path(path: &Path) -> (Option<u64>, Option<u64>, Option<u64>) {
+    let subtitle_id = path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| {
+            let name = if name.ends_with(".xml.gz") { &name[..name.len() - 3] } else { name };
+            if name.ends_with(".xml") { Some(&name[..name.len() - 4]) } else { None }
+        })
+        .and_then(|stem| stem.parse::<u64>().ok());
+    let mut components = path.components();
+    components.next_back();
+    let imdb_id = numeric_component(components.next_back());
+    let year = numeric_component(components.next_back())
+        .filter(|&year| year >= 1800 && year < 3000);
+    match (imdb_id, year, subtitle_id) {
+        (Some(imdb_id), Some(year), Some(subtitle_id)) =>
+            (Some(imdb_id), Some(year), Some(subtitle_id)),
+        _ => (None, None, None),
+    }
+}
+
+pub fn movie_key(path: &Path) -> MovieKey {
+    match ids_of_path(path).0 {
+        Some(imdb_id) => MovieKey::Imdb(imdb_id),
+        None => MovieKey::Path(path.to_owned()),
+    }
+}
+
+fn parse_id(field: &str) -> Result<Option<u64>, String> {
+    if field == "-" {
+        Ok(None)
+    } else {
+        field.parse::<u64>().map(Some).map_err(|_| format!("bad id '{}'", field))
+    }
+}
+
+fn show_id(id: Option<u64>) -> String {
+    id.map(|id| id.to_string()).unwrap_or_else(|| "-".to_owned())
+}
+
+fn parse_text(field: &str) -> Option<String> {
+    if field == "-" {
+        None
+    } else {
+        Some(field.to_owned())
+    }
+}
+
+fn show_text(text: &Option<String>) -> &str {
+    text.as_ref().map(|text| text.as_str()).unwrap_or("-")
+}
+
+/// Escapes what would break a registry line.
+fn escape_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undoes `escape_path`. A backslash before anything else is kept as it is.
+fn unescape_path(field: &str) -> String {
+    let mut path = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => path.push('\\'),
+            Some('t') => path.push('\t'),
+            Some('n') => path.push('\n'),
+            Some('r') => path.push('\r'),
+            Some(other) => {
+                path.push('\\');
+                path.push(other);
+            }
+            None => path.push('\\'),
+        }
+    }
+    path
+}
+
+/// The first of the metadata keys which the document has, with tabs and newlines made spaces so
+/// it fits in a registry line.
+fn meta_text(meta: &Meta, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .filter_map(|key| meta.get(key))
+        .find(|value| !value.is_empty())
+        .map(|value| value.replace(|c: char| c == '\t' || c == '\n' || c == '\r', " "))
+}
+
+impl Doc {
+    /// Fills in what the subtitle metadata says about the movie.
+    fn set_meta(&mut self, meta: &Meta) {
+        self.title = meta_text(meta, &["source.title", "source.original_title",
+                                       "subtitle.title"]);
+        // Durations are kept as written, since the source gives minutes and the subtitle a time
+        self.duration = meta_text(meta, &["source.duration", "subtitle.duration"]);
+        if !meta.source.genre.is_empty() {
+            self.genre = Some(meta.source.genre.join(", "));
+        }
+        if self.year.is_none() {
+            self.year = meta.source.year;
+        }
+        if self.imdb_id.is_none() {
+            self.imdb_id = meta.source.imdb_id;
+        }
+    }
+
+    /// A one line description like `The Man Without a Past (2002, Comedy, 97, IMDb 311519)`,
+    /// falling back on the path when there is no title.
+    pub fn describe(&self) -> String {
+        let mut details = vec![];
+        if let Some(year) = self.year {
+            details.push(year.to_string());
+        }
+        if let Some(ref genre) = self.genre {
+            details.push(genre.clone());
+        }
+        if let Some(ref duration) = self.duration {
+            details.push(duration.clone());
+        }
+        if let Some(imdb_id) = self.imdb_id {
+            details.push(format!("IMDb {}", imdb_id));
+        }
+        let name = self.title.as_ref().unwrap_or(&self.path);
+        if details.is_empty() {
+            name.clone()
+        } else {
+            format!("{} ({})", name, details.join(", "))
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{\"doc\":{},\"path\":{},\"title\":{},\"year\":{},\"duration\":{},\
+                 \"genre\":{},\"imdb_id\":{},\"subtitle_id\":{}}}",
+                self.doc_idx, json_str(&self.path), json_opt_str(&self.title),
+                json_opt_u64(self.year), json_opt_str(&self.duration), json_opt_str(&self.genre),
+                json_opt_u64(self.imdb_id), json_opt_u64(self.subtitle_id))
+    }
+}
+
+impl Registry {
+    /// Registers the chosen subtitle file of each movie, given by its path in the collection.
+    pub fn assign(chosen: &[(MovieKey, PathBuf)]) -> Registry {
+        let mut docs = BTreeMap::new();
+        let mut next_unnumbered = FIRST_UNNUMBERED_ID;
+        // Sorted by key, which puts the unnumbered files last and in order of path
+        for &(ref key, ref path) in chosen {
+            let (imdb_id, year, subtitle_id) = ids_of_path(path);
+            let doc_idx = match *key {
+                MovieKey::Imdb(imdb_id) => imdb_id,
+                MovieKey::Path(_) => {
+                    next_unnumbered += 1;
+                    next_unnumbered - 1
+                }
+            };
+            docs.insert(doc_idx, Doc {
+                doc_idx: doc_idx,
+                path: path.to_string_lossy().into_owned(),
+                imdb_id: imdb_id,
+                year: year,
+                subtitle_id: subtitle_id,
+                title: None,
+                duration: None,
+                genre: None,
+            });
+        }
+        Registry { docs: docs }
+    }
+
+    /// Drops the documents keep returns false for, like those the corpus filter rejected.
+    pub fn retain<F: FnMut(u64) -> bool>(&mut self, mut keep: F) {
+        let docs = mem::replace(&mut self.docs, BTreeMap::new());
+        self.docs = docs.into_iter().filter(|&(doc_idx, _)| keep(doc_idx)).collect();
+    }
+
+    pub fn set_meta(&mut self, doc_idx: u64, meta: &Meta) {
+        if let Some(doc) = self.docs.get_mut(&doc_idx) {
+            doc.set_meta(meta);
+        }
+    }
+
+    pub fn get(&self, doc_idx: u64) -> Option<&Doc> {
+        self.docs.get(&doc_idx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn docs<'a>(&'a self) -> Box<Iterator<Item=&'a Doc> + 'a> {
+        Box::new(self.docs.values())
+    }
+
+    /// A short human readable description of where a document came from.
+    pub fn describe(&self, doc_idx: u64) -> String {
+        match self.get(doc_idx) {
+            Some(doc) => doc.describe(),
+            None => format!("unregistered document {}", doc_idx),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for doc in self.docs.values() {
+            writeln!(buf, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", doc.doc_idx, escape_path(&doc.path),
+                     show_id(doc.imdb_id), show_id(doc.year), show_id(doc.subtitle_id),
+                     show_text(&doc.title), show_text(&doc.duration), show_text(&doc.genre))
+                .unwrap();
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Registry> {
+        let text = str::from_utf8(buf).map_err(invalid_data)?;
+        let mut docs = BTreeMap::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 8 {
+                return Err(invalid_data(format!("bad registry line '{}'", line)));
+            }
+            let doc = (|| -> Result<Doc, String> {
+                Ok(Doc {
+                    doc_idx: parse_id(fields[0])?.ok_or("missing document id")?,
+                    path: unescape_path(fields[1]),
+                    imdb_id: parse_id(fields[2])?,
+                    year: parse_id(fields[3])?,
+                    subtitle_id: parse_id(fields[4])?,
+                    title: parse_text(fields[5]),
+                    duration: parse_text(fields[6]),
+                    genre: parse_text(fields[7]),
+                })
+            })().map_err(|err| invalid_data(format!("{} in registry line '{}'", err, line)))?;
+            docs.insert(doc.doc_idx, doc);
+        }
+        Ok(Registry { docs: docs })
+    }
+
+    /// Reads the registry written next to output_fn, or None if there isn't one.
+    pub fn load(output_fn: &str) -> io::Result<Option<Registry>> {
+        let path = registry_path(output_fn);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let mut buf = vec![];
+        File::open(&path)?.read_to_end(&mut buf)?;
+        Registry::decode(&buf).map(Some)
+    }
+
+    /// Writes the registry next to output_fn.
+    pub fn save(&self, output_fn: &str) -> io::Result<()> {
+        File::create(registry_path(output_fn))?.write_all(&self.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use opensubtitles::Meta;
+    use super::{Registry, MovieKey, FIRST_UNNUMBERED_ID, ids_of_path, movie_key};
+
+    fn registry(paths: &[&str]) -> Registry {
+        let mut chosen = paths.iter()
+            .map(|path| (movie_key(Path::new(path)), PathBuf::from(path)))
+            .collect::<Vec<_>>();
+        chosen.sort();
+        Registry::assign(&chosen)
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(ids_of_path(Path::new("fi/2002/311519/3213.xml.gz")),
+                   (Some(311519), Some(2002), Some(3213)));
+        assert_eq!(ids_of_path(Path::new("2002/311519/3213.xml")),
+                   (Some(311519), Some(2002), Some(3213)));
+        assert_eq!(ids_of_path(Path::new("season1/01/a.srt")), (None, None, None));
+        assert_eq!(ids_of_path(Path::new("2002/311519/3213.srt")), (None, None, None));
+        assert_eq!(ids_of_path(Path::new("2002/311519/subs.xml")), (None, None, None));
+        // Not a year
+        assert_eq!(ids_of_path(Path::new("12/311519/3213.xml")), (None, None, None));
+        assert_eq!(ids_of_path(Path::new("311519/3213.xml")), (None, None, None));
+        assert_eq!(movie_key(Path::new("season1/01/a.srt")),
+                   MovieKey::Path(PathBuf::from("season1/01/a.srt")));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut registry = registry(&["fi/2002/311519/3213.xml.gz", "odd\tname\n.srt",
+                                      "back\\slash\\t.vtt"]);
+        let mut raw = BTreeMap::new();
+        raw.insert("source.title".to_owned(), "Mies\tvailla\nmenneisyyttä".to_owned());
+        raw.insert("source.duration".to_owned(), "97".to_owned());
+        raw.insert("source.genre".to_owned(), "Comedy, Drama".to_owned());
+        registry.set_meta(311519, &Meta::from_raw(raw));
+
+        let decoded = Registry::decode(&registry.encode()).unwrap();
+        assert_eq!(decoded.len(), 3);
+        let doc = decoded.get(311519).unwrap();
+        assert_eq!(doc.path, "fi/2002/311519/3213.xml.gz");
+        assert_eq!((doc.imdb_id, doc.year, doc.subtitle_id),
+                   (Some(311519), Some(2002), Some(3213)));
+        assert_eq!(doc.title, Some("Mies vailla menneisyyttä".to_owned()));
+        assert_eq!(doc.duration, Some("97".to_owned()));
+        assert_eq!(doc.genre, Some("Comedy, Drama".to_owned()));
+        // Unnumbered files come after, in order of path
+        let doc = decoded.get(FIRST_UNNUMBERED_ID).unwrap();
+        assert_eq!(doc.path, "back\\slash\\t.vtt");
+        let doc = decoded.get(FIRST_UNNUMBERED_ID + 1).unwrap();
+        assert_eq!(doc.path, "odd\tname\n.srt");
+        assert_eq!((doc.imdb_id, doc.year, doc.subtitle_id, &doc.title), (None, None, None, &None));
+        assert_eq!(decoded.encode(), registry.encode());
+    }
+
+    #[test]
+    fn bad_lines() {
+        assert!(Registry::decode(b"1\ta.srt\t-\t-\t-\t-\t-\n").is_err());
+        assert!(Registry::decode(b"x\ta.srt\t-\t-\t-\t-\t-\t-\n").is_err());
+        assert!(Registry::decode(b"-\ta.srt\t-\t-\t-\t-\t-\t-\n").is_err());
+        assert!(Registry::decode(b"1\ta.srt\t-\t-\t-\t-\t-\t-\n").is_ok());
+    }
+}
@@ -0,0 +1,291 @@
+//! Choosing one subtitle file per movie.
+//!
+//! OpenSubtitles has many versions of the subtitles for most movies. Which one gets indexed is
+//! decided by a selection policy: a comma separated list of criteria, each breaking the ties
+//! left by the ones before it. Remaining ties go to the first path in sorted order, so the choice
+//! never depends on the order the collection directory is walked in.
+//!
+//! Criteria:
+//!
+//! ```text
+//! original-finnish   prefer files whose source.original metadata mentions Finnish
+//! rating             prefer the highest subtitle.rating metadata
+//! downloads          prefer the highest subtitle.downloads metadata
+//! tokens             prefer the file with the most words
+//! ```
+//!
+//! A list of files to choose can be given as well. A movie with a listed file always gets it,
+//! whatever the policy says.
+//!
+//! Only movies with several files and no listed file are scanned, all in one pass over the
+//! collection.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+use itertools::Itertools;
+use opensubtitles::{ParseMode, Recovery, SubStreamBit, SubtitleSource, Tokenization};
+use collection::{Collection, ReadOptions};
+use registry::MovieKey;
+
+pub const DEFAULT_POLICY: &'static str = "original-finnish,rating,downloads,tokens";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Criterion {
+    OriginalFinnish,
+    Rating,
+    Downloads,
+    Tokens,
+}
+
+pub struct SelectionPolicy {
+    criteria: Vec<Criterion>,
+    chosen: HashSet<PathBuf>,
+}
+
+/// What is known about one candidate file.
+#[derive(Debug, Default)]
+struct Candidate {
+    original_finnish: bool,
+    rating: f64,
+    downloads: u64,
+    tokens: u64,
+}
+
+impl SelectionPolicy {
+    pub fn parse(policy: &str) -> Result<SelectionPolicy, String> {
+        let criteria = policy.split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| match name {
+                "original-finnish" => Ok(Criterion::OriginalFinnish),
+                "rating" => Ok(Criterion::Rating),
+                "downloads" => Ok(Criterion::Downloads),
+                "tokens" => Ok(Criterion::Tokens),
+                _ => Err(format!("unknown selection criterion '{}'", name)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SelectionPolicy { criteria: criteria, chosen: HashSet::new() })
+    }
+
+    /// Reads a file listing one subtitle path per line to always choose. Paths are relative to
+    /// the collection. Blank lines and lines starting with # are ignored.
+    pub fn read_chosen(&mut self, chosen_fn: &str) -> Result<(), String> {
+        let chosen_f = File::open(chosen_fn)
+            .map_err(|err| format!("can't open {}: {}", chosen_fn, err))?;
+        for line in BufReader::new(chosen_f).lines() {
+            let line = line.map_err(|err| format!("can't read {}: {}", chosen_fn, err))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.chosen.insert(PathBuf::from(line));
+        }
+        Ok(())
+    }
+
+    /// A description of the policy for the manifest.
+    pub fn describe(&self) -> String {
+        let mut description = self.criteria.iter()
+            .map(|criterion| match *criterion {
+                Criterion::OriginalFinnish => "original-finnish",
+                Criterion::Rating => "rating",
+                Criterion::Downloads => "downloads",
+                Criterion::Tokens => "tokens",
+            })
+            .join(",");
+        if !self.chosen.is_empty() {
+            description.push_str(&format!(" (with {} chosen files)", self.chosen.len()));
+        }
+        description
+    }
+
+    fn needs_scan(&self) -> bool {
+        !self.criteria.is_empty()
+    }
+
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        // Greater is better
+        for criterion in &self.criteria {
+            let ordering = match *criterion {
+                Criterion::OriginalFinnish => a.original_finnish.cmp(&b.original_finnish),
+                Criterion::Rating => a.rating.partial_cmp(&b.rating).unwrap_or(Ordering::Equal),
+                Criterion::Downloads => a.downloads.cmp(&b.downloads),
+                Criterion::Tokens => a.tokens.cmp(&b.tokens),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+
+    fn needs_choice(&self, paths: &[PathBuf]) -> bool {
+        paths.len() > 1 && !paths.iter().any(|path| self.chosen.contains(path))
+    }
+
+    /// Picks one of the candidate paths, which must be sorted.
+    fn choose(&self, paths: &[PathBuf], candidates: &HashMap<PathBuf, Candidate>) -> usize {
+        if let Some(idx) = paths.iter().position(|path| self.chosen.contains(path)) {
+            return idx;
+        }
+        if paths.len() == 1 || !self.needs_scan() {
+            return 0;
+        }
+        let unknown = Candidate::default();
+        let candidate = |idx: usize| candidates.get(&paths[idx]).unwrap_or(&unknown);
+        let mut best = 0;
+        for idx in 1..paths.len() {
+            // Only strictly better candidates replace the best, so ties go to the earlier path
+            if self.compare(candidate(idx), candidate(best)) == Ordering::Greater {
+                best = idx;
+            }
+        }
+        best
+    }
+
+    /// Chooses one subtitle file for each movie, logging each choice among several files.
+    pub fn select(&self, mut movies: BTreeMap<MovieKey, Vec<PathBuf>>, collection: &Collection,
+                  tokenization: Tokenization) -> io::Result<Vec<(MovieKey, PathBuf)>> {
+        for paths in movies.values_mut() {
+            paths.sort();
+        }
+        let to_scan = if self.needs_scan() {
+            movies.values()
+                .filter(|paths| self.needs_choice(paths))
+                .flat_map(|paths| paths.iter().cloned())
+                .collect_vec()
+        } else {
+            vec![]
+        };
+        let options = ReadOptions {
+            mode: ParseMode::Lax,
+            recovery: Recovery::Skip,
+            tokenization: tokenization,
+        };
+        let candidates: HashMap<PathBuf, Candidate> = collection
+            .read(&to_scan, options, |_, ss| scan(ss))?
+            .into_iter()
+            .collect();
+        Ok(movies.into_iter()
+            .map(|(movie_key, mut paths)| {
+                let choice = self.choose(&paths, &candidates);
+                if paths.len() > 1 {
+                    println!("Movie {}: chose {} out of {} files",
+                             movie_key, paths[choice].to_string_lossy(), paths.len());
+                }
+                (movie_key, paths.swap_remove(choice))
+            })
+            .collect())
+    }
+}
+
+fn scan(ss: io::Result<Box<SubtitleSource>>) -> Candidate {
+    // Unreadable files are left with the default, worst, scores
+    let mut candidate = Candidate::default();
+    let mut ss = match ss {
+        Ok(ss) => ss,
+        Err(_) => return candidate,
+    };
+    // Words up to the first error still count
+    candidate.tokens = (&mut ss)
+        .take_while(|bit| bit.is_ok())
+        .filter(|bit| match *bit {
+            Ok(SubStreamBit::Word(_)) => true,
+            _ => false,
+        })
+        .count() as u64;
+    if let Some(meta) = ss.meta() {
+        candidate.original_finnish = meta.source.original.as_ref()
+            .map(|original| original.contains("Finnish"))
+            .unwrap_or(false);
+        candidate.rating = meta.subtitle.rating.unwrap_or(0.0);
+        candidate.downloads = meta.get("subtitle.downloads")
+            .and_then(|downloads| downloads.parse::<u64>().ok())
+            .unwrap_or(0);
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use super::*;
+
+    fn paths() -> Vec<PathBuf> {
+        vec![PathBuf::from("2002/311519/1.xml.gz"),
+             PathBuf::from("2002/311519/2.xml.gz"),
+             PathBuf::from("2002/311519/3.xml.gz")]
+    }
+
+    fn candidates(scores: &[(bool, f64, u64, u64)]) -> HashMap<PathBuf, Candidate> {
+        paths().into_iter()
+            .zip(scores)
+            .map(|(path, &(original_finnish, rating, downloads, tokens))| {
+                (path, Candidate {
+                    original_finnish: original_finnish,
+                    rating: rating,
+                    downloads: downloads,
+                    tokens: tokens,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(SelectionPolicy::parse(DEFAULT_POLICY).unwrap().describe(), DEFAULT_POLICY);
+        assert_eq!(SelectionPolicy::parse(" tokens, rating ,").unwrap().describe(),
+                   "tokens,rating");
+        assert!(SelectionPolicy::parse("rating,popularity").is_err());
+    }
+
+    #[test]
+    fn criteria_in_order() {
+        let candidates = candidates(&[(false, 9.0, 500, 900),
+                                      (true, 5.0, 100, 800),
+                                      (true, 7.0, 10, 700)]);
+        let choose = |policy: &str| {
+            SelectionPolicy::parse(policy).unwrap().choose(&paths(), &candidates)
+        };
+        assert_eq!(choose(DEFAULT_POLICY), 2);
+        assert_eq!(choose("original-finnish,downloads"), 1);
+        assert_eq!(choose("rating"), 0);
+        assert_eq!(choose("tokens,original-finnish"), 0);
+    }
+
+    #[test]
+    fn ties_go_to_the_first_path() {
+        let candidates = candidates(&[(false, 5.0, 10, 700),
+                                      (true, 5.0, 10, 700),
+                                      (true, 5.0, 10, 700)]);
+        let choose = |policy: &str| {
+            SelectionPolicy::parse(policy).unwrap().choose(&paths(), &candidates)
+        };
+        assert_eq!(choose(DEFAULT_POLICY), 1);
+        assert_eq!(choose("rating,downloads,tokens"), 0);
+        assert_eq!(choose(""), 0);
+        // Files which couldn't be scanned lose to any which could
+        let mut candidates = candidates;
+        candidates.remove(&paths()[0]);
+        assert_eq!(SelectionPolicy::parse("tokens").unwrap().choose(&paths(), &candidates), 1);
+    }
+
+    #[test]
+    fn chosen_files_win() {
+        let candidates = candidates(&[(true, 9.0, 500, 900),
+                                      (false, 0.0, 0, 0),
+                                      (false, 0.0, 0, 0)]);
+        let mut policy = SelectionPolicy::parse(DEFAULT_POLICY).unwrap();
+        policy.chosen.insert(paths()[2].clone());
+        assert_eq!(policy.choose(&paths(), &candidates), 2);
+        assert!(!policy.needs_choice(&paths()));
+        assert!(policy.needs_choice(&paths()[..2]));
+        assert!(!policy.needs_choice(&paths()[..1]));
+    }
+}
@@ -0,0 +1,285 @@
+//! Statistics about an index, printed by the `stats` subcommand as text or JSON.
+//!
+//! Everything is counted from the postings, so stopped terms don't contribute. Term and
+//! collection statistics from `preindex` are included as they are when available.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use fst::{Map, Streamer};
+use itertools::Itertools;
+use json::{json_str, json_f64};
+use manifest::Manifest;
+use postings::PostingsSource;
+use registry::Registry;
+use termstats::{StatsReader, CollectionStats};
+
+struct TermCounts {
+    term: String,
+    df: u64,
+    cf: u64,
+}
+
+#[derive(Default)]
+struct DocCounts {
+    tokens: u64,
+    vocabulary: u64,
+}
+
+struct Summary {
+    min: u64,
+    max: u64,
+    mean: f64,
+    median: u64,
+}
+
+/// A least squares fit of y = slope * x + intercept.
+struct Fit {
+    slope: f64,
+    intercept: f64,
+    r2: f64,
+}
+
+fn summarize<I: Iterator<Item=u64>>(values: I) -> Option<Summary> {
+    let values = values.sorted();
+    if values.is_empty() {
+        return None;
+    }
+    Some(Summary {
+        min: values[0],
+        max: values[values.len() - 1],
+        mean: values.iter().sum::<u64>() as f64 / values.len() as f64,
+        median: values[values.len() / 2],
+    })
+}
+
+fn fit(points: &[(f64, f64)]) -> Option<Fit> {
+    let n = points.len() as f64;
+    if points.len() < 2 {
+        return None;
+    }
+    let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let sxx = points.iter().map(|&(x, _)| (x - mean_x) * (x - mean_x)).sum::<f64>();
+    let sxy = points.iter().map(|&(x, y)| (x - mean_x) * (y - mean_y)).sum::<f64>();
+    let syy = points.iter().map(|&(_, y)| (y - mean_y) * (y - mean_y)).sum::<f64>();
+    if sxx == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    Some(Fit {
+        slope: slope,
+        intercept: mean_y - slope * mean_x,
+        r2: if syy == 0.0 { 1.0 } else { sxy * sxy / (sxx * syy) },
+    })
+}
+
+pub struct IndexStats {
+    manifest: Option<BTreeMap<String, String>>,
+    collection: Option<CollectionStats>,
+    terms: Vec<TermCounts>,
+    docs: BTreeMap<u64, DocCounts>,
+    /// The registry entry of each document as JSON, when there is a registry
+    movies: BTreeMap<u64, String>,
+    /// Number of terms with a postings list of length in [2^i, 2^(i+1))
+    postings_histogram: Vec<u64>,
+    zipf: Option<Fit>,
+    heaps: Option<Fit>,
+}
+
+impl IndexStats {
+    pub fn new(map: &Map, postings_source: &PostingsSource, manifest: Option<&Manifest>,
+               registry: Option<&Registry>, term_stats: Option<&StatsReader>) -> IndexStats {
+        let mut terms = Vec::with_capacity(map.len());
+        let mut docs: BTreeMap<u64, DocCounts> = BTreeMap::new();
+        // The first document each term occurs in, to trace vocabulary growth
+        let mut first_docs: HashMap<u64, u64> = HashMap::new();
+        let mut postings_histogram = vec![];
+        let mut stream = map.stream();
+        while let Some((term, value)) = stream.next() {
+            let term = String::from_utf8_lossy(term).into_owned();
+            let postings = postings_source.postings(value)
+                .unwrap_or_else(|err| panic!("Corrupt postings list for {}: {}", term, err));
+            let mut df = 0;
+            for (doc_idx, group) in postings.iter().group_by(|posting| posting.doc_idx).into_iter() {
+                let doc = docs.entry(doc_idx).or_insert_with(DocCounts::default);
+                doc.tokens += group.count() as u64;
+                doc.vocabulary += 1;
+                df += 1;
+            }
+            if let Some(first) = postings.first() {
+                *first_docs.entry(first.doc_idx).or_insert(0) += 1;
+            }
+            let cf = postings.len() as u64;
+            let bucket = (64 - cf.leading_zeros() as usize).saturating_sub(1);
+            if postings_histogram.len() <= bucket {
+                postings_histogram.resize(bucket + 1, 0);
+            }
+            postings_histogram[bucket] += 1;
+            terms.push(TermCounts { term: term, df: df, cf: cf });
+        }
+
+        let zipf_points = terms.iter()
+            .map(|term| term.cf)
+            .sorted_by(|a, b| b.cmp(a))
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, cf)| cf > 0)
+            .map(|(rank, cf)| (((rank + 1) as f64).ln(), (cf as f64).ln()))
+            .collect_vec();
+        let mut heaps_points = vec![];
+        let mut num_tokens = 0;
+        let mut vocabulary = 0;
+        for (doc_idx, doc) in &docs {
+            num_tokens += doc.tokens;
+            vocabulary += first_docs.get(doc_idx).cloned().unwrap_or(0);
+            heaps_points.push(((num_tokens as f64).ln(), (vocabulary as f64).ln()));
+        }
+
+        IndexStats {
+            manifest: manifest.map(|manifest| manifest.to_map()),
+            collection: term_stats.and_then(|term_stats| term_stats.collection()),
+            terms: terms,
+            movies: registry
+                .map(|registry| registry.docs().map(|doc| (doc.doc_idx, doc.to_json())).collect())
+                .unwrap_or_else(BTreeMap::new),
+            docs: docs,
+            postings_histogram: postings_histogram,
+            zipf: fit(&zipf_points),
+            heaps: fit(&heaps_points),
+        }
+    }
+
+    fn num_postings(&self) -> u64 {
+        self.terms.iter().map(|term| term.cf).sum()
+    }
+
+    fn num_hapaxes(&self) -> u64 {
+        self.terms.iter().filter(|term| term.cf == 1).count() as u64
+    }
+
+    fn hapax_ratio(&self) -> f64 {
+        self.num_hapaxes() as f64 / self.terms.len() as f64
+    }
+
+    fn top_by<F: Fn(&TermCounts) -> u64>(&self, top_n: usize, key: F) -> Vec<&TermCounts> {
+        let mut top = self.terms.iter().collect_vec();
+        top.sort_by(|a, b| key(b).cmp(&key(a)).then_with(|| a.term.cmp(&b.term)));
+        top.truncate(top_n);
+        top
+    }
+
+    pub fn print_text(&self, top_n: usize) {
+        match self.manifest {
+            Some(ref manifest) => {
+                for (key, value) in manifest {
+                    println!("Manifest {}: {}", key, value);
+                }
+            }
+            None => println!("No manifest"),
+        }
+        println!("Size of dictionary: {}", self.terms.len());
+        if let Some(ref collection) = self.collection {
+            println!("Documents in collection: {}", collection.num_docs);
+            println!("Tokens in collection: {}", collection.num_tokens);
+            println!("Terms in collection: {}", collection.num_terms);
+            println!("Average document length: {:.1}",
+                     collection.num_tokens as f64 / collection.num_docs as f64);
+        }
+        println!("Total number of docs: {}", self.docs.len());
+        println!("Total number of postings: {}", self.num_postings());
+        println!("Hapax legomena: {} ({:.3} of terms)", self.num_hapaxes(), self.hapax_ratio());
+        let summaries = [
+            ("Indexed tokens per document", summarize(self.docs.values().map(|doc| doc.tokens))),
+            ("Vocabulary per document", summarize(self.docs.values().map(|doc| doc.vocabulary))),
+        ];
+        for &(name, ref summary) in &summaries {
+            if let Some(ref summary) = *summary {
+                println!("{}: min {} median {} mean {:.1} max {}",
+                         name, summary.min, summary.median, summary.mean, summary.max);
+            }
+        }
+        if let Some(ref zipf) = self.zipf {
+            println!("Zipf fit: cf = {:.1} * rank ^ -{:.3} (r^2 {:.3})",
+                     zipf.intercept.exp(), -zipf.slope, zipf.r2);
+        }
+        if let Some(ref heaps) = self.heaps {
+            println!("Heaps fit: vocabulary = {:.1} * tokens ^ {:.3} (r^2 {:.3})",
+                     heaps.intercept.exp(), heaps.slope, heaps.r2);
+        }
+        println!("Postings list lengths:");
+        for (bucket, &num_terms) in self.postings_histogram.iter().enumerate() {
+            println!("  {}-{}: {}", 1u64 << bucket, (1u64 << (bucket + 1)) - 1, num_terms);
+        }
+        println!("Top {} terms by df:", top_n);
+        for term in self.top_by(top_n, |term| term.df) {
+            println!("  {} df {} cf {}", term.term, term.df, term.cf);
+        }
+        println!("Top {} terms by cf:", top_n);
+        for term in self.top_by(top_n, |term| term.cf) {
+            println!("  {} cf {} df {}", term.term, term.cf, term.df);
+        }
+    }
+
+    pub fn print_json(&self, top_n: usize) {
+        let mut out = String::new();
+        out.push('{');
+        write!(out, "\"manifest\":").unwrap();
+        match self.manifest {
+            Some(ref manifest) => {
+                out.push('{');
+                let fields = manifest.iter()
+                    .map(|(key, value)| format!("{}:{}", json_str(key), json_str(value)))
+                    .join(",");
+                out.push_str(&fields);
+                out.push('}');
+            }
+            None => out.push_str("null"),
+        }
+        write!(out, ",\"collection\":").unwrap();
+        match self.collection {
+            Some(ref collection) => {
+                write!(out, "{{\"num_docs\":{},\"num_tokens\":{},\"num_terms\":{}}}",
+                       collection.num_docs, collection.num_tokens, collection.num_terms).unwrap()
+            }
+            None => out.push_str("null"),
+        }
+        write!(out, ",\"dictionary_size\":{},\"num_docs\":{},\"num_postings\":{}",
+               self.terms.len(), self.docs.len(), self.num_postings()).unwrap();
+        write!(out, ",\"hapax_legomena\":{},\"hapax_ratio\":{}",
+               self.num_hapaxes(), json_f64(self.hapax_ratio())).unwrap();
+        write!(out, ",\"zipf\":{},\"heaps\":{}",
+               json_fit(self.zipf.as_ref()), json_fit(self.heaps.as_ref())).unwrap();
+        let histogram = self.postings_histogram.iter().enumerate()
+            .map(|(bucket, num_terms)| format!("{{\"min\":{},\"max\":{},\"terms\":{}}}",
+                                               1u64 << bucket, (1u64 << (bucket + 1)) - 1,
+                                               num_terms))
+            .join(",");
+        write!(out, ",\"postings_lengths\":[{}]", histogram).unwrap();
+        for &(name, ref top) in &[("top_by_df", self.top_by(top_n, |term| term.df)),
+                                  ("top_by_cf", self.top_by(top_n, |term| term.cf))] {
+            let terms = top.iter()
+                .map(|term| format!("{{\"term\":{},\"df\":{},\"cf\":{}}}",
+                                    json_str(&term.term), term.df, term.cf))
+                .join(",");
+            write!(out, ",\"{}\":[{}]", name, terms).unwrap();
+        }
+        let docs = self.docs.iter()
+            .map(|(doc_idx, doc)| {
+                format!("{{\"doc\":{},\"tokens\":{},\"vocabulary\":{},\"movie\":{}}}",
+                        doc_idx, doc.tokens, doc.vocabulary,
+                        self.movies.get(doc_idx).map(|movie| movie.as_str()).unwrap_or("null"))
+            })
+            .join(",");
+        write!(out, ",\"docs\":[{}]", docs).unwrap();
+        out.push('}');
+        println!("{}", out);
+    }
+}
+
+fn json_fit(fit: Option<&Fit>) -> String {
+    match fit {
+        Some(fit) => format!("{{\"slope\":{},\"intercept\":{},\"r2\":{}}}",
+                             json_f64(fit.slope), json_f64(fit.intercept), json_f64(fit.r2)),
+        None => "null".to_owned(),
+    }
+}
@@ -0,0 +1,145 @@
+//! Term and document statistics.
+//!
+//! `preindex` writes these to an LMDB database alongside the preindex. Every key starts with a
+//! byte giving the record type:
+//!
+//! ```text
+//! key                              value
+//! 'C'                              u64(num_docs) u64(num_tokens) u64(num_terms)
+//! 'T' term                         u64(df) u64(cf)
+//! 'F' term 0x00 u64(doc_idx)       u64(tf)
+//! 'D' u64(doc_idx)                 u64(doc_len)
+//! ```
+//!
+//! All integers are big-endian. df is the number of distinct documents a term occurs in, cf the
+//! number of times it occurs in the whole collection, tf the number of times it occurs in one
+//! document and doc_len the number of tokens in a document. Tokens are the words of the
+//! subtitles, so lemmas indexed alongside them count towards df, cf and tf but not doc_len or
+//! num_tokens.
+
+use byteorder::{BigEndian, ByteOrder};
+use lmdb::{Database, MdbError, MdbResult};
+
+const COLLECTION_KEY: &'static [u8] = b"C";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TermStats {
+    pub df: u64,
+    pub cf: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollectionStats {
+    pub num_docs: u64,
+    pub num_tokens: u64,
+    pub num_terms: u64,
+}
+
+fn term_key(term: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(term.len() + 1);
+    key.push(b'T');
+    key.extend_from_slice(term.as_bytes());
+    key
+}
+
+fn tf_key(term: &str, doc_idx: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(term.len() + 10);
+    key.push(b'F');
+    key.extend_from_slice(term.as_bytes());
+    key.push(0);
+    key.extend_from_slice(&encode_u64s(&[doc_idx]));
+    key
+}
+
+fn doc_key(doc_idx: u64) -> Vec<u8> {
+    let mut key = vec![b'D'];
+    key.extend_from_slice(&encode_u64s(&[doc_idx]));
+    key
+}
+
+fn encode_u64s(values: &[u64]) -> Vec<u8> {
+    let mut buf = vec![0; values.len() * 8];
+    for (idx, &value) in values.iter().enumerate() {
+        BigEndian::write_u64(&mut buf[idx * 8..], value);
+    }
+    buf
+}
+
+fn decode_u64s(key: &[u8], buf: &[u8], num: usize) -> Vec<u64> {
+    if buf.len() != num * 8 {
+        panic!("Corrupt statistics record for key {:?}: expected {} bytes but got {}",
+               String::from_utf8_lossy(key), num * 8, buf.len());
+    }
+    buf.chunks(8).map(BigEndian::read_u64).collect()
+}
+
+pub struct StatsWriter<'a>(pub &'a Database<'a>);
+
+impl<'a> StatsWriter<'a> {
+    pub fn collection(&self, stats: &CollectionStats) -> MdbResult<()> {
+        self.0.set(&COLLECTION_KEY,
+                   &encode_u64s(&[stats.num_docs, stats.num_tokens, stats.num_terms]))
+    }
+
+    pub fn term(&self, term: &str, stats: &TermStats) -> MdbResult<()> {
+        self.0.set(&term_key(term), &encode_u64s(&[stats.df, stats.cf]))
+    }
+
+    pub fn tf(&self, term: &str, doc_idx: u64, tf: u64) -> MdbResult<()> {
+        self.0.set(&tf_key(term, doc_idx), &encode_u64s(&[tf]))
+    }
+
+    pub fn doc_len(&self, doc_idx: u64, doc_len: u64) -> MdbResult<()> {
+        self.0.set(&doc_key(doc_idx), &encode_u64s(&[doc_len]))
+    }
+}
+
+pub struct StatsReader<'a>(pub &'a Database<'a>);
+
+impl<'a> StatsReader<'a> {
+    fn get(&self, key: &[u8], num: usize) -> Option<Vec<u64>> {
+        match self.0.get::<Vec<u8>>(&key) {
+            Ok(buf) => Some(decode_u64s(key, &buf, num)),
+            Err(MdbError::NotFound) => None,
+            Err(err) => panic!("Error reading statistics: {}", err),
+        }
+    }
+
+    pub fn collection(&self) -> Option<CollectionStats> {
+        self.get(COLLECTION_KEY, 3).map(|values| CollectionStats {
+            num_docs: values[0],
+            num_tokens: values[1],
+            num_terms: values[2],
+        })
+    }
+
+    pub fn term(&self, term: &str) -> Option<TermStats> {
+        self.get(&term_key(term), 2).map(|values| TermStats {
+            df: values[0],
+            cf: values[1],
+        })
+    }
+
+    pub fn tf(&self, term: &str, doc_idx: u64) -> u64 {
+        self.get(&tf_key(term, doc_idx), 1).map(|values| values[0]).unwrap_or(0)
+    }
+
+    pub fn doc_len(&self, doc_idx: u64) -> Option<u64> {
+        self.get(&doc_key(doc_idx), 1).map(|values| values[0])
+    }
+
+    /// Every document index along with its length, in order.
+    pub fn docs(&self) -> Vec<(u64, u64)> {
+        let start = vec![b'D'];
+        let mut docs = vec![];
+        for cur in self.0.keyrange_from(&start).unwrap() {
+            let key = cur.get_key::<&[u8]>();
+            if key.first() != Some(&b'D') {
+                break;
+            }
+            let doc_idx = decode_u64s(key, &key[1..], 1)[0];
+            docs.push((doc_idx, decode_u64s(key, cur.get_value::<&[u8]>(), 1)[0]));
+        }
+        docs
+    }
+}
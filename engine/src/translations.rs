@@ -0,0 +1,188 @@
+//! Translations of the indexed sentences.
+//!
+//! `align` reads an OPUS sentence alignment between the indexed subtitles and subtitles in
+//! another language, like `en-fi.xml.gz`, and writes the text of the sentences aligned to each
+//! indexed sentence to an LMDB database:
+//!
+//! ```text
+//! key                              value
+//! u64(doc_idx) u64(snt_idx)        text
+//! ```
+//!
+//! Integers are big-endian and text is UTF-8. Several sentences aligned to one indexed sentence
+//! are joined by spaces. Alignment documents are matched up with the indexed ones and the files
+//! of the other collection by the OpenSubtitles subtitle id at the start of their file names, so
+//! neither the layout of the collections nor `.xml` versus `.xml.gz` matters.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use byteorder::{BigEndian, ByteOrder};
+use itertools::Itertools;
+use lmdb::{Database, MdbError, MdbResult};
+use opensubtitles::{AlignmentStream, ParseMode, Recovery, Sentences, Tokenization};
+use collection::{Collection, ReadOptions};
+use registry::{Registry, ids_of_path};
+use {Staged, OutputKind, new_db_txn, fail, read_or_fail};
+
+fn translation_key(doc_idx: u64, snt_idx: u64) -> Vec<u8> {
+    let mut key = vec![0; 16];
+    BigEndian::write_u64(&mut key[..8], doc_idx);
+    BigEndian::write_u64(&mut key[8..], snt_idx);
+    key
+}
+
+pub struct TranslationsWriter<'a>(pub &'a Database<'a>);
+
+impl<'a> TranslationsWriter<'a> {
+    pub fn translation(&self, doc_idx: u64, snt_idx: u64, text: &str) -> MdbResult<()> {
+        self.0.set(&translation_key(doc_idx, snt_idx), &text.as_bytes().to_vec())
+    }
+}
+
+pub struct TranslationsReader<'a>(pub &'a Database<'a>);
+
+impl<'a> TranslationsReader<'a> {
+    pub fn translation(&self, doc_idx: u64, snt_idx: u64) -> Option<String> {
+        match self.0.get::<Vec<u8>>(&translation_key(doc_idx, snt_idx)) {
+            Ok(buf) => Some(String::from_utf8_lossy(&buf).into_owned()),
+            Err(MdbError::NotFound) => None,
+            Err(err) => panic!("Error reading translations: {}", err),
+        }
+    }
+}
+
+/// Joins tokens back into text, without the spaces tokenization put before punctuation.
+fn detokenize<'a, I: Iterator<Item=&'a str>>(tokens: I) -> String {
+    let mut text = String::new();
+    for token in tokens {
+        let attaches = token.chars().all(|c| ".,!?;:)]}%…".contains(c)) ||
+            token.starts_with('\'') || text.ends_with('(') || text.ends_with('[');
+        if !text.is_empty() && !attaches {
+            text.push(' ');
+        }
+        text.push_str(token);
+    }
+    text
+}
+
+/// The subtitle id of an OpenSubtitles path like `fi/2002/311519/3213.xml.gz`.
+fn subtitle_id(path: &str) -> Option<u64> {
+    ids_of_path(Path::new(path)).2
+}
+
+/// The sentences of one file of the other collection which translate indexed sentences, as
+/// (doc_idx, indexed sentence ids, translating sentence ids).
+type Wanted = Vec<(u64, Vec<u64>, Vec<u64>)>;
+
+pub fn align(registry: &Registry, alignment_fn: &str, collection_fn: &str, translations_fn: &str,
+             map_size: usize, force: bool) {
+    let translations_out = Staged::new(translations_fn, OutputKind::Lmdb, force);
+    let indexed: HashMap<u64, u64> = registry.docs()
+        .filter_map(|doc| doc.subtitle_id.map(|subtitle_id| (subtitle_id, doc.doc_idx)))
+        .collect();
+    let collection = Collection::open(collection_fn).unwrap_or_else(|err| fail(&err));
+    let others: HashMap<u64, PathBuf> = read_or_fail(collection_fn, collection.list())
+        .into_iter()
+        .filter_map(|name| ids_of_path(&name).2.map(|subtitle_id| (subtitle_id, name)))
+        .collect();
+    println!("{} indexed documents, {} files to translate from", indexed.len(), others.len());
+
+    let alignment = AlignmentStream::from_path(alignment_fn)
+        .unwrap_or_else(|err| fail(&format!("can't open {}: {}", alignment_fn, err)))
+        .with_recovery(Recovery::Skip);
+    let mut wanted: HashMap<PathBuf, Wanted> = HashMap::new();
+    let mut num_groups = 0;
+    let mut num_matched = 0;
+    let mut num_missing = 0;
+    for group in alignment {
+        let group = group.unwrap_or_else(|err| fail(&format!("{}: {}", alignment_fn, err)));
+        num_groups += 1;
+        // OPUS orders the languages alphabetically, so the indexed documents can be on either
+        // side
+        let from_doc = subtitle_id(&group.from_doc).and_then(|id| indexed.get(&id));
+        let to_doc = subtitle_id(&group.to_doc).and_then(|id| indexed.get(&id));
+        let (doc_idx, other, indexed_is_to) = match (from_doc, to_doc) {
+            (_, Some(&doc_idx)) => (doc_idx, &group.from_doc, true),
+            (Some(&doc_idx), None) => (doc_idx, &group.to_doc, false),
+            (None, None) => continue,
+        };
+        num_matched += 1;
+        let other = match subtitle_id(other).and_then(|id| others.get(&id)) {
+            Some(other) => other,
+            None => {
+                num_missing += 1;
+                continue;
+            }
+        };
+        let links = wanted.entry(other.clone()).or_insert_with(Vec::new);
+        for link in group.links {
+            let (indexed_ids, other_ids) = if indexed_is_to {
+                (link.to, link.from)
+            } else {
+                (link.from, link.to)
+            };
+            if !indexed_ids.is_empty() && !other_ids.is_empty() {
+                links.push((doc_idx, indexed_ids, other_ids));
+            }
+        }
+    }
+    println!("{} link groups, {} for indexed documents, {} of which have no file to translate \
+              from", num_groups, num_matched, num_missing);
+
+    let names = wanted.keys().cloned().sorted();
+    let options = ReadOptions {
+        mode: ParseMode::Lax,
+        recovery: Recovery::Skip,
+        tokenization: Tokenization::Words,
+    };
+    let translated = collection.read(&names, options, |name, ss| {
+        let mut ss = match ss {
+            Ok(ss) => ss,
+            Err(err) => {
+                println!("Skipping {}: {}", name.to_string_lossy(), err);
+                return vec![];
+            }
+        };
+        let mut texts = HashMap::new();
+        for sentence in Sentences::new(&mut ss) {
+            match sentence {
+                Ok(sentence) => {
+                    let text = detokenize(sentence.words.iter().map(|word| word.word.as_str()));
+                    texts.insert(sentence.id, text);
+                }
+                Err(err) => {
+                    // The sentences before the error can still be used
+                    println!("Warning: {}: {}", name.to_string_lossy(), err);
+                    break;
+                }
+            }
+        }
+        let mut translations = vec![];
+        for &(doc_idx, ref indexed_ids, ref other_ids) in &wanted[name] {
+            let text = other_ids.iter()
+                .filter_map(|other_id| texts.get(other_id))
+                .join(" ");
+            if text.is_empty() {
+                continue;
+            }
+            for &snt_idx in indexed_ids {
+                translations.push((doc_idx, snt_idx, text.clone()));
+            }
+        }
+        translations
+    });
+    let mut translations = read_or_fail(collection_fn, translated).into_iter()
+        .flat_map(|(_, translations)| translations)
+        .collect_vec();
+    translations.sort();
+    println!("{} translated sentences", translations.len());
+
+    new_db_txn(translations_out.tmp(), map_size, |_txn, translations_db| {
+        let writer = TranslationsWriter(translations_db);
+        for &(doc_idx, snt_idx, ref text) in &translations {
+            writer.translation(doc_idx, snt_idx, text)?;
+        }
+        Ok(())
+    });
+    translations_out.commit();
+}
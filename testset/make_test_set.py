@@ -123,12 +123,12 @@ def main():
 
         # Check word has right distribution
         occurs_possible = []
-        tdf_db = sys.argv[3]
-        with lmdb.open(tdf_db) as env:
+        stats_db = sys.argv[3]
+        with lmdb.open(stats_db) as env:
             txn = env.begin()
             for token in possible:
-                occurs = txn.get(token.encode('utf-8'))
-                (occurs,) = struct.unpack('<Q', occurs)
+                stats = txn.get(b'T' + token.encode('utf-8'))
+                (occurs, _cf) = struct.unpack('>QQ', stats)
                 if 2 <= occurs < 30:
                     #print(token, occurs)
                     occurs_possible.append(token)